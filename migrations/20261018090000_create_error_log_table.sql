@@ -0,0 +1,37 @@
+CREATE TABLE error_log (
+  id UUID NOT NULL,
+  event_ts TIMESTAMP WITH TIME ZONE NOT NULL,
+  PRIMARY KEY (id, event_ts),
+
+  hostname TEXT,
+
+  level TEXT NOT NULL,
+  pid INTEGER,
+  tid BIGINT,
+  connection_id BIGINT,
+  message TEXT NOT NULL,
+
+  client TEXT,
+  server TEXT,
+  request TEXT,
+  subrequest TEXT,
+  upstream TEXT,
+  host TEXT,
+  referrer TEXT,
+
+  syslog_facility TEXT,
+  syslog_severity TEXT,
+  syslog_tag TEXT,
+  syslog_ts TIMESTAMP WITH TIME ZONE,
+  source_addr INET
+);
+
+CREATE INDEX error_log_hostname_idx ON error_log(hostname);
+CREATE INDEX error_log_level_idx ON error_log(level);
+
+DO $$ BEGIN
+  IF EXISTS(SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+    PERFORM create_hypertable('error_log', 'event_ts');
+    PERFORM add_retention_policy('error_log', INTERVAL '365 days');
+  END IF;
+END $$;
@@ -0,0 +1,6 @@
+ALTER TABLE access_log
+  ADD COLUMN syslog_facility TEXT,
+  ADD COLUMN syslog_severity TEXT,
+  ADD COLUMN syslog_tag TEXT,
+  ADD COLUMN syslog_ts TIMESTAMP WITH TIME ZONE,
+  ADD COLUMN source_addr INET;
@@ -0,0 +1,19 @@
+CREATE TABLE rejected_datagram (
+  id UUID NOT NULL,
+  received_ts TIMESTAMP WITH TIME ZONE NOT NULL,
+  PRIMARY KEY (id, received_ts),
+
+  source_addr INET,
+
+  payload BYTEA NOT NULL,
+  payload_text TEXT NOT NULL,
+  error TEXT NOT NULL
+);
+
+-- Retention is handled by the bridge itself, since it's configurable via
+-- REJECTED_RETENTION_DAYS.
+DO $$ BEGIN
+  IF EXISTS(SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+    PERFORM create_hypertable('rejected_datagram', 'received_ts');
+  END IF;
+END $$;
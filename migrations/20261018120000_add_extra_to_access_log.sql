@@ -0,0 +1,2 @@
+ALTER TABLE access_log
+  ADD COLUMN extra JSONB;
@@ -0,0 +1,3 @@
+ALTER TABLE access_log ALTER COLUMN hostname DROP NOT NULL;
+
+UPDATE access_log SET hostname = NULL WHERE hostname = '';
@@ -0,0 +1,8 @@
+ALTER TABLE access_log
+  ADD COLUMN upstream_addr_attempts TEXT[],
+  ADD COLUMN upstream_bytes_received_attempts BIGINT[],
+  ADD COLUMN upstream_bytes_sent_attempts BIGINT[],
+  ADD COLUMN upstream_connect_time_attempts FLOAT[],
+  ADD COLUMN upstream_response_length_attempts BIGINT[],
+  ADD COLUMN upstream_response_time_attempts FLOAT[],
+  ADD COLUMN upstream_status_attempts INTEGER[];
@@ -0,0 +1,28 @@
+-- nginx always includes the port in `$upstream_addr`, and wraps IPv6
+-- addresses in brackets, like `[2001:db8::1]:80`. Unix sockets look like
+-- `unix:/run/app.sock`, and have no address or port.
+ALTER TABLE access_log
+  ADD COLUMN upstream_port INTEGER,
+  ADD COLUMN upstream_is_unix BOOLEAN;
+
+UPDATE access_log SET
+  upstream_port = CASE WHEN upstream_addr NOT LIKE 'unix:%'
+    THEN substring(upstream_addr FROM ':(\d+)$')::integer END,
+  upstream_is_unix = upstream_addr LIKE 'unix:%'
+WHERE upstream_addr IS NOT NULL;
+
+-- Anything that isn't a valid address, which shouldn't exist, ends up as NULL
+-- instead of failing the migration.
+CREATE FUNCTION pg_temp.try_inet(value TEXT) RETURNS INET AS $$
+BEGIN
+  RETURN value::inet;
+EXCEPTION WHEN invalid_text_representation THEN
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE;
+
+ALTER TABLE access_log
+  ALTER COLUMN client_addr TYPE INET USING pg_temp.try_inet(client_addr),
+  ALTER COLUMN upstream_addr TYPE INET USING pg_temp.try_inet(
+    regexp_replace(upstream_addr, '^\[?(.*?)\]?:\d+$', '\1')
+  );
@@ -0,0 +1,3 @@
+ALTER TABLE access_log
+  ADD COLUMN client_forwarded_for_addrs INET[],
+  ADD COLUMN client_real_addr INET;
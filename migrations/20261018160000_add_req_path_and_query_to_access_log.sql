@@ -0,0 +1,8 @@
+ALTER TABLE access_log
+  ADD COLUMN req_path TEXT,
+  ADD COLUMN req_query TEXT;
+
+UPDATE access_log SET
+  req_path = split_part(req_uri, '?', 1),
+  req_query = NULLIF(substring(req_uri FROM '\?(.*)$'), '')
+WHERE req_uri IS NOT NULL;
@@ -0,0 +1,6 @@
+ALTER TABLE access_log
+  ADD COLUMN ua_browser TEXT,
+  ADD COLUMN ua_browser_version TEXT,
+  ADD COLUMN ua_os TEXT,
+  ADD COLUMN ua_device TEXT,
+  ADD COLUMN ua_is_bot BOOLEAN;
@@ -0,0 +1,6 @@
+ALTER TABLE access_log
+  ADD COLUMN geo_country_code TEXT,
+  ADD COLUMN geo_region TEXT,
+  ADD COLUMN geo_city TEXT,
+  ADD COLUMN geo_asn BIGINT,
+  ADD COLUMN geo_as_org TEXT;
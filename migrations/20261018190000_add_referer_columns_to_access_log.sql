@@ -0,0 +1,42 @@
+ALTER TABLE access_log
+  ADD COLUMN referer_scheme TEXT,
+  ADD COLUMN referer_host TEXT,
+  ADD COLUMN referer_path TEXT,
+  ADD COLUMN referer_is_internal BOOLEAN;
+
+-- Splits client_referer of existing rows the same way the bridge does for new
+-- ones. referer_is_internal is left NULL, since OWN_DOMAINS isn't known here.
+UPDATE access_log SET
+  referer_scheme = parsed.scheme,
+  referer_host = parsed.host,
+  referer_path = parsed.path
+FROM (
+  SELECT
+    id,
+    lower(parts[1]) AS scheme,
+    NULLIF(lower(rtrim(
+      CASE
+        WHEN host_and_port LIKE '[%' THEN substring(host_and_port FROM '^\[([^\]]*)')
+        ELSE regexp_replace(host_and_port, ':[0-9]*$', '')
+      END,
+      '.'
+    )), '') AS host,
+    COALESCE(NULLIF(parts[3], ''), '/') AS path
+  FROM (
+    SELECT
+      id,
+      parts,
+      regexp_replace(parts[2], '^.*@', '') AS host_and_port
+    FROM (
+      SELECT
+        id,
+        regexp_match(btrim(client_referer), '^([A-Za-z][A-Za-z0-9+.-]*)://([^/?#]*)([^?#]*)') AS parts
+      FROM access_log
+      WHERE client_referer IS NOT NULL
+    ) AS matched
+    WHERE parts IS NOT NULL
+  ) AS split
+) AS parsed
+WHERE access_log.id = parsed.id;
+
+CREATE INDEX access_log_referer_host_idx ON access_log(referer_host);
@@ -0,0 +1,2 @@
+ALTER TABLE access_log
+  ADD COLUMN sample_rate DOUBLE PRECISION NOT NULL DEFAULT 1;
@@ -1,8 +1,14 @@
+use std::{fmt::Display, net::IpAddr};
+
 use chrono::{DateTime, Utc};
+use serde_json::Value;
 use sqlx::{Postgres, postgres::PgArguments, query::Query};
 use uuid::Uuid;
 
-use crate::parsers::AccessLogEntry;
+use crate::{
+    column_vecs::{array_literal, column_vecs_impl, column_vecs_push_body},
+    parsers::{AccessLogEntry, UpstreamAddr},
+};
 
 /// This is a bit painful. Since we'll be using batch inserts via
 /// `INSERT INTO ... SELECT * FROM UNNEST`, we need to have each column as its
@@ -11,11 +17,11 @@ use crate::parsers::AccessLogEntry;
 /// this too painful, but meh.
 pub struct AccessLogColumnVecs {
     pub id: Vec<Uuid>,
-    pub hostname: Vec<String>,
+    pub hostname: Vec<Option<String>>,
     pub ts: Vec<DateTime<Utc>>,
     pub server_name: Vec<Option<String>>,
     pub server_port: Vec<Option<i32>>,
-    pub client_addr: Vec<Option<String>>,
+    pub client_addr: Vec<Option<IpAddr>>,
     pub client_forwarded_for: Vec<Option<String>>,
     pub client_referer: Vec<Option<String>>,
     pub client_ua: Vec<Option<String>>,
@@ -29,7 +35,9 @@ pub struct AccessLogColumnVecs {
     pub res_duration: Vec<Option<f64>>,
     pub res_length: Vec<Option<i64>>,
     pub res_status: Vec<Option<i32>>,
-    pub upstream_addr: Vec<Option<String>>,
+    pub upstream_addr: Vec<Option<IpAddr>>,
+    pub upstream_port: Vec<Option<i32>>,
+    pub upstream_is_unix: Vec<Option<bool>>,
     pub upstream_bytes_received: Vec<Option<i64>>,
     pub upstream_bytes_sent: Vec<Option<i64>>,
     pub upstream_cache_status: Vec<Option<String>>,
@@ -38,90 +46,111 @@ pub struct AccessLogColumnVecs {
     pub upstream_response_length: Vec<Option<i64>>,
     pub upstream_response_time: Vec<Option<f64>>,
     pub upstream_status: Vec<Option<i32>>,
-}
-
-/// This macro just exists to reduce pain with defining the functions that are
-/// just boring calls to something where the only difference is the field name.
-macro_rules! column_vecs_impl {
-    (
-        $($field:ident),* $(,)?
-    ) => {
-        impl AccessLogColumnVecs {
-            pub fn with_capacity(capacity: usize) -> Self {
-                Self {
-                    $(
-                        $field: Vec::with_capacity(capacity),
-                    )*
-                }
-            }
-
-            pub fn clear(&mut self) {
-                $(
-                    self.$field.clear();
-                )*
-            }
-
-            pub fn bind_all<'q>(
-                &'q self,
-                mut query: Query<'q, Postgres, PgArguments>,
-            ) -> Query<'q, Postgres, PgArguments> {
-                $(
-                    query = query.bind(&self.$field);
-                )*
-                query
-            }
-
-        }
-    }
-}
-
-/// Likewise, this is just a glorified string replace to make assigning values
-/// in the push() fn a bit less verbose.
-macro_rules! column_vecs_push_body {
-    (
-        $self:ident, $entry:ident, {
-            $($field:ident: $expr:expr),* $(,)?
-        }
-    ) => {
-        $(
-            $self.$field.push($expr);
-        )*
-    }
+    pub upstream_addr_attempts: Vec<Option<String>>,
+    pub upstream_bytes_received_attempts: Vec<Option<String>>,
+    pub upstream_bytes_sent_attempts: Vec<Option<String>>,
+    pub upstream_connect_time_attempts: Vec<Option<String>>,
+    pub upstream_response_length_attempts: Vec<Option<String>>,
+    pub upstream_response_time_attempts: Vec<Option<String>>,
+    pub upstream_status_attempts: Vec<Option<String>>,
+    pub syslog_facility: Vec<Option<String>>,
+    pub syslog_severity: Vec<Option<String>>,
+    pub syslog_tag: Vec<Option<String>>,
+    pub syslog_ts: Vec<Option<DateTime<Utc>>>,
+    pub source_addr: Vec<Option<IpAddr>>,
+    pub extra: Vec<Option<Value>>,
+    pub client_forwarded_for_addrs: Vec<Option<String>>,
+    pub client_real_addr: Vec<Option<IpAddr>>,
+    pub req_path: Vec<Option<String>>,
+    pub req_query: Vec<Option<String>>,
+    pub ua_browser: Vec<Option<String>>,
+    pub ua_browser_version: Vec<Option<String>>,
+    pub ua_os: Vec<Option<String>>,
+    pub ua_device: Vec<Option<String>>,
+    pub ua_is_bot: Vec<Option<bool>>,
+    pub geo_country_code: Vec<Option<String>>,
+    pub geo_region: Vec<Option<String>>,
+    pub geo_city: Vec<Option<String>>,
+    pub geo_asn: Vec<Option<i64>>,
+    pub geo_as_org: Vec<Option<String>>,
+    pub referer_scheme: Vec<Option<String>>,
+    pub referer_host: Vec<Option<String>>,
+    pub referer_path: Vec<Option<String>>,
+    pub referer_is_internal: Vec<Option<bool>>,
+    pub sample_rate: Vec<f64>,
 }
 
 column_vecs_impl! {
-    id,
-    hostname,
-    ts,
-    server_name,
-    server_port,
-    client_addr,
-    client_forwarded_for,
-    client_referer,
-    client_ua,
-    req_host,
-    req_length,
-    req_method,
-    req_proto,
-    req_scheme,
-    req_uri,
-    res_body_length,
-    res_duration,
-    res_length,
-    res_status,
-    upstream_addr,
-    upstream_bytes_received,
-    upstream_bytes_sent,
-    upstream_cache_status,
-    upstream_connect_time,
-    upstream_host,
-    upstream_response_length,
-    upstream_response_time,
-    upstream_status,
+    AccessLogColumnVecs {
+        id => id::uuid,
+        hostname => hostname::text,
+        ts => event_ts::timestamptz,
+        server_name => server_name::text,
+        server_port => server_port::int4,
+        client_addr => client_addr::inet,
+        client_forwarded_for => client_forwarded_for::text,
+        client_referer => client_referer::text,
+        client_ua => client_ua::text,
+        req_host => req_host::text,
+        req_length => req_length::int8,
+        req_method => req_method::text,
+        req_proto => req_proto::text,
+        req_scheme => req_scheme::text,
+        req_uri => req_uri::text,
+        res_body_length => res_body_length::int8,
+        res_duration => res_duration::float8,
+        res_length => res_length::int8,
+        res_status => res_status::int4,
+        upstream_addr => upstream_addr::inet,
+        upstream_port => upstream_port::int4,
+        upstream_is_unix => upstream_is_unix::bool,
+        upstream_bytes_received => upstream_bytes_received::int8,
+        upstream_bytes_sent => upstream_bytes_sent::int8,
+        upstream_cache_status => upstream_cache_status::text,
+        upstream_connect_time => upstream_connect_time::float8,
+        upstream_host => upstream_host::text,
+        upstream_response_length => upstream_response_length::int8,
+        upstream_response_time => upstream_response_time::float8,
+        upstream_status => upstream_status::int4,
+        upstream_addr_attempts => upstream_addr_attempts::text as "text[]",
+        upstream_bytes_received_attempts => upstream_bytes_received_attempts::text as "int8[]",
+        upstream_bytes_sent_attempts => upstream_bytes_sent_attempts::text as "int8[]",
+        upstream_connect_time_attempts => upstream_connect_time_attempts::text as "float8[]",
+        upstream_response_length_attempts => upstream_response_length_attempts::text as "int8[]",
+        upstream_response_time_attempts => upstream_response_time_attempts::text as "float8[]",
+        upstream_status_attempts => upstream_status_attempts::text as "int4[]",
+        syslog_facility => syslog_facility::text,
+        syslog_severity => syslog_severity::text,
+        syslog_tag => syslog_tag::text,
+        syslog_ts => syslog_ts::timestamptz,
+        source_addr => source_addr::inet,
+        extra => extra::jsonb,
+        client_forwarded_for_addrs => client_forwarded_for_addrs::text as "inet[]",
+        client_real_addr => client_real_addr::inet,
+        req_path => req_path::text,
+        req_query => req_query::text,
+        ua_browser => ua_browser::text,
+        ua_browser_version => ua_browser_version::text,
+        ua_os => ua_os::text,
+        ua_device => ua_device::text,
+        ua_is_bot => ua_is_bot::bool,
+        geo_country_code => geo_country_code::text,
+        geo_region => geo_region::text,
+        geo_city => geo_city::text,
+        geo_asn => geo_asn::int8,
+        geo_as_org => geo_as_org::text,
+        referer_scheme => referer_scheme::text,
+        referer_host => referer_host::text,
+        referer_path => referer_path::text,
+        referer_is_internal => referer_is_internal::bool,
+        sample_rate => sample_rate::float8,
+    }
 }
 
 impl AccessLogColumnVecs {
     pub fn push(&mut self, entry: AccessLogEntry) {
+        let upstream_addr =
+            last_attempt(&entry.upstream.addr).map(|addr| UpstreamAddr::parse(&addr));
         column_vecs_push_body!(self, entry, {
             id: Uuid::new_v4(),
             ts: entry.ts,
@@ -142,15 +171,60 @@ impl AccessLogColumnVecs {
             res_duration: entry.res.duration,
             res_length: entry.res.length,
             res_status: entry.res.status,
-            upstream_addr: entry.upstream.addr,
-            upstream_bytes_received: entry.upstream.bytes_received,
-            upstream_bytes_sent: entry.upstream.bytes_sent,
+            upstream_addr: upstream_addr.as_ref().and_then(|addr| addr.ip),
+            upstream_port: upstream_addr.as_ref().and_then(|addr| addr.port),
+            upstream_is_unix: upstream_addr.as_ref().map(|addr| addr.is_unix),
+            upstream_bytes_received: last_attempt(&entry.upstream.bytes_received),
+            upstream_bytes_sent: last_attempt(&entry.upstream.bytes_sent),
             upstream_cache_status: entry.upstream.cache_status,
-            upstream_connect_time: entry.upstream.connect_time,
+            upstream_connect_time: last_attempt(&entry.upstream.connect_time),
             upstream_host: entry.upstream.host,
-            upstream_response_length: entry.upstream.response_length,
-            upstream_response_time: entry.upstream.response_time,
-            upstream_status: entry.upstream.status,
+            upstream_response_length: last_attempt(&entry.upstream.response_length),
+            upstream_response_time: last_attempt(&entry.upstream.response_time),
+            upstream_status: last_attempt(&entry.upstream.status),
+            upstream_addr_attempts: attempts(&entry.upstream.addr),
+            upstream_bytes_received_attempts: attempts(&entry.upstream.bytes_received),
+            upstream_bytes_sent_attempts: attempts(&entry.upstream.bytes_sent),
+            upstream_connect_time_attempts: attempts(&entry.upstream.connect_time),
+            upstream_response_length_attempts: attempts(&entry.upstream.response_length),
+            upstream_response_time_attempts: attempts(&entry.upstream.response_time),
+            upstream_status_attempts: attempts(&entry.upstream.status),
+            syslog_facility: entry.syslog.facility,
+            syslog_severity: entry.syslog.severity,
+            syslog_tag: entry.syslog.tag,
+            syslog_ts: entry.syslog.ts,
+            source_addr: entry.syslog.source_addr,
+            extra: (!entry.extra.is_empty()).then_some(Value::Object(entry.extra)),
+            client_forwarded_for_addrs: (!entry.enrichment.forwarded_for_addrs.is_empty())
+                .then(|| array_literal(&entry.enrichment.forwarded_for_addrs)),
+            client_real_addr: entry.enrichment.real_addr,
+            req_path: entry.enrichment.req_path,
+            req_query: entry.enrichment.req_query,
+            ua_browser: entry.enrichment.user_agent.browser,
+            ua_browser_version: entry.enrichment.user_agent.browser_version,
+            ua_os: entry.enrichment.user_agent.os,
+            ua_device: entry.enrichment.user_agent.device,
+            ua_is_bot: entry.enrichment.user_agent.is_bot,
+            geo_country_code: entry.enrichment.location.country_code,
+            geo_region: entry.enrichment.location.region,
+            geo_city: entry.enrichment.location.city,
+            geo_asn: entry.enrichment.location.asn,
+            geo_as_org: entry.enrichment.location.as_org,
+            referer_scheme: entry.enrichment.referer.scheme,
+            referer_host: entry.enrichment.referer.host,
+            referer_path: entry.enrichment.referer.path,
+            referer_is_internal: entry.enrichment.referer.is_internal,
+            sample_rate: entry.sample_rate.unwrap_or(1.0),
         });
     }
 }
+
+/// The existing upstream columns only contain the value of the last attempt,
+/// which is the one that produced the response.
+fn last_attempt<T: Clone>(values: &[Option<T>]) -> Option<T> {
+    values.last().cloned().flatten()
+}
+
+fn attempts<T: Display>(values: &[Option<T>]) -> Option<String> {
+    (!values.is_empty()).then(|| array_literal(values))
+}
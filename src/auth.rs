@@ -0,0 +1,187 @@
+use std::{borrow::Cow, fmt, sync::LazyLock};
+
+use hmac::{Hmac, Mac};
+use regex::bytes::Regex;
+use sha2::Sha256;
+use subtle::{Choice, ConstantTimeEq};
+
+use crate::settings::Settings;
+
+/// The RFC5424 structured data element that carries a message's signature,
+/// like `[auth sig="<hex>"]`.
+const SD_ID: &str = "auth";
+const SD_SIGNATURE_PARAM: &str = "sig";
+
+/// The `auth` field of an access_log entry, including its value. This works
+/// on raw bytes, since it's also used for payloads that aren't valid UTF-8.
+static TOKEN_FIELD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#""auth"\s*:\s*"(?:[^"\\]|\\.)*""#).expect("token regex to be valid")
+});
+
+/// Returned if a message fails the authenticity check, so it can be told
+/// apart from other parsing errors.
+#[derive(Debug)]
+pub struct AuthError(&'static str);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "authentication failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Shared secrets that incoming messages have to be signed with, or carry
+/// as a token. Any of the keys is accepted, so keys can be rotated without
+/// losing messages: add the new key, update all senders, remove the old key.
+#[derive(Clone, Debug, Default)]
+pub struct AuthKeys {
+    keys: Vec<Vec<u8>>,
+}
+
+impl AuthKeys {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            keys: settings
+                .auth_keys
+                .iter()
+                .map(|key| key.as_bytes().to_vec())
+                .collect(),
+        }
+    }
+
+    /// Checks if a message is authentic. If no keys are configured, every
+    /// message is. Otherwise, the message either needs a structured data
+    /// element like `[auth sig="<hex>"]`, with the hex-encoded HMAC-SHA256 of
+    /// the message body as the signature, or a `token` that is equal to one of
+    /// the keys. Since nginx can't sign anything on its own, the token is meant
+    /// for access_log entries that include a key in their JSON.
+    pub fn verify(
+        &self,
+        syslog: &syslog_loose::Message<&str>,
+        token: Option<&str>,
+    ) -> Result<(), AuthError> {
+        if self.keys.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(signature) = signature(syslog) {
+            let signature = hex::decode(signature.trim())
+                .map_err(|_| AuthError("signature is not valid hex"))?;
+            return self
+                .keys
+                .iter()
+                .any(|key| {
+                    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+                        .expect("HMAC to accept keys of any length");
+                    mac.update(syslog.msg.as_bytes());
+                    mac.verify_slice(&signature).is_ok()
+                })
+                .then_some(())
+                .ok_or(AuthError("signature does not match any key"));
+        }
+
+        if let Some(token) = token {
+            // All keys are compared, even if an earlier one matched, so the
+            // time this takes doesn't tell which key it was.
+            let matched = self.keys.iter().fold(Choice::from(0), |matched, key| {
+                matched | key.as_slice().ct_eq(token.as_bytes())
+            });
+            return bool::from(matched)
+                .then_some(())
+                .ok_or(AuthError("token does not match any key"));
+        }
+
+        Err(AuthError("message has neither a signature nor a token"))
+    }
+}
+
+/// Replaces the value of the `auth` field in a payload with `[REDACTED]`, so
+/// the key doesn't end up in the database when the payload gets stored as it
+/// was received.
+pub fn redact_token(payload: &[u8]) -> Cow<'_, [u8]> {
+    TOKEN_FIELD.replace_all(payload, &br#""auth":"[REDACTED]""#[..])
+}
+
+fn signature(syslog: &syslog_loose::Message<&str>) -> Option<String> {
+    syslog
+        .structured_data
+        .iter()
+        .find(|element| element.id == SD_ID)?
+        .params()
+        .find(|(name, _)| **name == SD_SIGNATURE_PARAM)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keys(keys: &[&str]) -> AuthKeys {
+        AuthKeys {
+            keys: keys.iter().map(|key| key.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    fn sign(key: &str, msg: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(msg.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn parse(datagram: &str) -> syslog_loose::Message<&str> {
+        syslog_loose::parse_message(datagram, syslog_loose::Variant::Either)
+    }
+
+    #[test]
+    fn accepts_everything_without_keys() {
+        let syslog = parse("<190>Aug 16 18:35:53 nginx: hello");
+        assert!(keys(&[]).verify(&syslog, None).is_ok());
+    }
+
+    #[test]
+    fn accepts_signature_from_any_key() {
+        let datagram = format!(
+            r#"<190>1 2022-08-16T18:35:53Z web01 nginx - - [auth sig="{}"] hello"#,
+            sign("new", "hello")
+        );
+        let syslog = parse(&datagram);
+        assert!(keys(&["old", "new"]).verify(&syslog, None).is_ok());
+        assert!(keys(&["old"]).verify(&syslog, None).is_err());
+    }
+
+    #[test]
+    fn rejects_signature_for_different_message() {
+        let datagram = format!(
+            r#"<190>1 2022-08-16T18:35:53Z web01 nginx - - [auth sig="{}"] goodbye"#,
+            sign("key", "hello")
+        );
+        assert!(keys(&["key"]).verify(&parse(&datagram), None).is_err());
+    }
+
+    #[test]
+    fn checks_tokens() {
+        let syslog = parse("<190>Aug 16 18:35:53 nginx: hello");
+        let keys = keys(&["old", "new"]);
+        assert!(keys.verify(&syslog, Some("old")).is_ok());
+        assert!(keys.verify(&syslog, Some("new")).is_ok());
+        assert!(keys.verify(&syslog, Some("meow")).is_err());
+        assert!(keys.verify(&syslog, None).is_err());
+    }
+
+    #[test]
+    fn redacts_tokens() {
+        assert_eq!(
+            br#"<190>nginx: {"auth":"[REDACTED]","ts":"1"}"#[..],
+            *redact_token(br#"<190>nginx: {"auth" : "s3\"cr3t","ts":"1"}"#)
+        );
+        assert_eq!(
+            b"<190>nginx: {\"auth\":\"[REDACTED]\",\"x\":\"\xff\"}"[..],
+            *redact_token(b"<190>nginx: {\"auth\":\"s3cr3t\",\"x\":\"\xff\"}")
+        );
+        assert_eq!(
+            br#"<190>nginx: {"authority":"s3cr3t"}"#[..],
+            *redact_token(br#"<190>nginx: {"authority":"s3cr3t"}"#)
+        );
+    }
+}
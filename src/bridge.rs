@@ -1,17 +1,56 @@
-use anyhow::{Error, Result};
-use sqlx::{PgPool, postgres::PgQueryResult};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    string::FromUtf8Error,
+    sync::Arc,
+};
+
+use anyhow::{Error, Result, anyhow, bail};
+use chrono::Utc;
+use metrics::{counter, gauge, histogram};
+use sqlx::{PgConnection, PgPool, Postgres, postgres::PgArguments, query::Query};
 use tokio::{
-    net::{UdpSocket, UnixDatagram},
-    sync::mpsc::{Receiver, Sender, channel},
-    time::Duration,
+    io::{AsyncBufRead, BufReader},
+    net::{TcpListener, UdpSocket, UnixDatagram},
+    sync::mpsc::{Receiver, Sender, channel, error::TrySendError},
+    task::JoinSet,
+    time::{Duration, Instant, MissedTickBehavior},
 };
 use tracing::{debug, error, info, trace, warn};
 
-use crate::{AccessLogColumnVecs, parsers::AccessLogEntry, settings::Settings};
+use crate::{
+    AccessLogColumnVecs, ErrorLogColumnVecs, RejectedDatagramColumnVecs,
+    auth::{AuthError, AuthKeys},
+    column_vecs::unnest_insert_sql,
+    datagram::Datagram,
+    enrichment::Enricher,
+    field_mapping::FieldMapping,
+    instrumentation,
+    mapped_column_vecs::MappedColumnVecs,
+    parsers::{AccessLogEntry, ErrorLogEntry, LogEntry, SyslogMetadata},
+    retry_policy::{self, RetryPolicy},
+    sampling_rules::{Decision, SamplingRules},
+    settings::{ParsingMode, Settings},
+    source_filter::SourceFilter,
+    spool::Spool,
+    tcp_framing,
+    tls::ReloadableTlsConfig,
+};
+
+/// How often the storer checks the spool for segments to replay. Only one
+/// segment gets replayed per tick, so live traffic doesn't get stuck behind a
+/// large spool.
+const SPOOL_REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Clients that don't finish the TLS handshake within this time get
+/// disconnected, so they can't keep connections open forever.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub enum SyslogSocket {
     Udp(UdpSocket),
     Unix(UnixDatagram),
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ReloadableTlsConfig>),
 }
 
 impl From<UdpSocket> for SyslogSocket {
@@ -26,14 +65,27 @@ impl From<UnixDatagram> for SyslogSocket {
     }
 }
 
-impl SyslogSocket {
-    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<String>)> {
+impl From<TcpListener> for SyslogSocket {
+    fn from(value: TcpListener) -> Self {
+        Self::Tcp(value)
+    }
+}
+
+/// The [SyslogSocket]s that receive datagrams, as opposed to accepting
+/// connections.
+enum DatagramSocket<'a> {
+    Udp(&'a UdpSocket),
+    Unix(&'a UnixDatagram),
+}
+
+impl DatagramSocket<'_> {
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<SocketAddr>)> {
         match self {
-            SyslogSocket::Udp(socket) => {
+            DatagramSocket::Udp(socket) => {
                 let (len, addr) = socket.recv_from(buf).await?;
-                Ok((len, Some(addr.to_string())))
+                Ok((len, Some(addr)))
             }
-            SyslogSocket::Unix(socket) => {
+            DatagramSocket::Unix(socket) => {
                 let (len, _) = socket.recv_from(buf).await?;
                 Ok((len, None))
             }
@@ -44,43 +96,142 @@ impl SyslogSocket {
 pub struct Bridge {}
 
 impl Bridge {
-    pub async fn run(db_pool: PgPool, settings: Settings, socket: SyslogSocket) -> Result<()> {
-        let (tx, rx) = channel::<String>(settings.queue_size);
+    /// Receives log lines on all sockets, and stores them, until `shutdown`
+    /// completes. After that, the sockets are closed, and everything that's
+    /// still queued gets stored, as long as that happens within
+    /// SHUTDOWN_TIMEOUT. Returns an error if that final flush failed or timed
+    /// out. If a receiver stops on its own, like after a panic, the same
+    /// happens, but an error is returned either way.
+    pub async fn run(
+        db_pool: PgPool,
+        settings: Settings,
+        sockets: Vec<SyslogSocket>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        if sockets.is_empty() {
+            bail!("the bridge needs at least one socket to listen on");
+        }
+
+        let field_mapping = FieldMapping::from_settings(&settings)?;
+        field_mapping.migrate(&db_pool).await?;
+        let enricher = Enricher::from_settings(&settings)?;
+        let sampling_rules = SamplingRules::from_settings(&settings)?;
 
-        let receiver = SyslogReceiver::new(tx, socket);
-        let receiving_loop = tokio::spawn(async move { receiver.run().await });
+        let (tx, rx) = channel::<Datagram>(settings.queue_size);
+
+        // Every socket gets its own receiver, but they all feed into the
+        // same queue and storer.
+        let source_filter = Arc::new(SourceFilter::from_settings(&settings));
+        let mut receiving_loops = JoinSet::new();
+        for socket in sockets {
+            let receiver = SyslogReceiver::new(
+                tx.clone(),
+                socket,
+                settings.max_message_size,
+                source_filter.clone(),
+            );
+            receiving_loops.spawn(async move { receiver.run().await });
+        }
+        drop(tx);
+
+        if settings.store_rejected {
+            tokio::spawn(prune_rejected_datagrams(
+                db_pool.clone(),
+                settings.rejected_retention_days,
+            ));
+        }
+
+        let spool = match &settings.spool_dir {
+            Some(dir) => {
+                Some(Spool::open(dir.clone(), settings.spool_max_size * 1024 * 1024).await?)
+            }
+            None => None,
+        };
 
         let mut queue_item_storer = QueueItemStorer::new(
             db_pool,
-            settings.insert_batch_size,
-            settings.insert_timeout,
+            &settings,
+            field_mapping,
+            enricher,
+            sampling_rules,
+            spool,
             rx,
         );
-        let storing_loop = tokio::spawn(async move { queue_item_storer.run().await });
+        let mut storing_loop = tokio::spawn(async move { queue_item_storer.run().await });
 
-        tokio::select! {
-            _ = receiving_loop => {},
-            _ = storing_loop => {},
+        // Receivers only ever stop if something went badly wrong, like a
+        // panic. What's already queued still gets stored in that case.
+        let stopped_receiver = tokio::select! {
+            result = receiving_loops.join_next() => Some(result),
+            result = &mut storing_loop => return result?,
+            _ = shutdown => None,
         };
 
-        Ok(())
+        // Dropping the receivers also drops their ends of the channel, which
+        // makes the storer exit once it has stored everything that's left.
+        match stopped_receiver {
+            Some(_) => error!("A receiver stopped unexpectedly, storing pending datagrams..."),
+            None => info!("Shutting down, storing pending datagrams..."),
+        }
+        receiving_loops.abort_all();
+
+        let shutdown_timeout = Duration::from_millis(settings.shutdown_timeout);
+        let flushed = match tokio::time::timeout(shutdown_timeout, storing_loop).await {
+            Ok(result) => result.map_err(Error::from).and_then(|result| result),
+            Err(_) => Err(anyhow!(
+                "storing the pending datagrams did not finish in time"
+            )),
+        };
+
+        let Some(stopped_receiver) = stopped_receiver else {
+            return flushed;
+        };
+        if let Err(err) = flushed {
+            error!("{:#}", err);
+        }
+        match stopped_receiver {
+            Some(Err(err)) => Err(Error::from(err).context("a receiver failed")),
+            _ => bail!("a receiver stopped unexpectedly"),
+        }
     }
 }
 
 pub struct SyslogReceiver {
-    received_sender: Sender<String>,
+    received_sender: Sender<Datagram>,
     socket: SyslogSocket,
+    max_message_size: usize,
+    source_filter: Arc<SourceFilter>,
 }
 
 impl SyslogReceiver {
-    pub fn new(received_sender: Sender<String>, socket: SyslogSocket) -> Self {
+    pub fn new(
+        received_sender: Sender<Datagram>,
+        socket: SyslogSocket,
+        max_message_size: usize,
+        source_filter: Arc<SourceFilter>,
+    ) -> Self {
         Self {
             received_sender,
             socket,
+            max_message_size,
+            source_filter,
         }
     }
 
     pub async fn run(&self) {
+        match &self.socket {
+            SyslogSocket::Udp(socket) => self.receive_datagrams(DatagramSocket::Udp(socket)).await,
+            SyslogSocket::Unix(socket) => {
+                self.receive_datagrams(DatagramSocket::Unix(socket)).await
+            }
+            SyslogSocket::Tcp(listener) => self.accept_connections(listener, None).await,
+            SyslogSocket::Tls(listener, tls_config) => {
+                self.accept_connections(listener, Some(tls_config)).await
+            }
+        }
+    }
+
+    async fn receive_datagrams(&self, socket: DatagramSocket<'_>) {
         // As per RFC5426, a syslog-via-udp message can only ever be one UDP
         // datagram long, not more. So we know the maximum ever length of that,
         // and the size is small enough to just allocate everything. The limit
@@ -88,94 +239,544 @@ impl SyslogReceiver {
         let mut buf = [0; 65535];
 
         loop {
-            if let Ok((len, addr)) = self.socket.recv_from(&mut buf).await {
+            if let Ok((len, addr)) = socket.recv_from(&mut buf).await {
+                let received_at = Utc::now();
+                counter!(instrumentation::DATAGRAMS_RECEIVED).increment(1);
+                counter!(instrumentation::BYTES_RECEIVED).increment(len as u64);
                 match addr {
                     Some(addr) => debug!("Received {} bytes from {}", len, addr),
                     None => debug!("Received {} bytes", len),
                 }
 
+                if let Some(addr) = addr
+                    && !self.source_filter.accepts_message(addr.ip())
+                {
+                    continue;
+                }
+
                 let buf = buf[0..len].to_owned();
                 let tx_clone = self.received_sender.clone();
                 tokio::spawn(async move {
-                    if let Ok(line) = String::from_utf8(buf) {
-                        trace!("Raw message: `{}`", line);
-                        // Silently drop send errors. This will fail if
-                        // There's too much traffic, but if that's the case,
-                        // spamming things to STDOUT doesn't help.
-                        let _ = tx_clone.try_send(line);
+                    let payload = decode_payload(buf);
+
+                    // Silently drop send errors. This will fail if
+                    // There's too much traffic, but if that's the case,
+                    // spamming things to STDOUT doesn't help. The metrics
+                    // will tell, though.
+                    let result = tx_clone.try_send(Datagram {
+                        received_at,
+                        source_addr: addr,
+                        payload,
+                    });
+                    if let Err(TrySendError::Full(_)) = result {
+                        counter!(instrumentation::QUEUE_FULL_DROPS).increment(1);
                     }
+                    gauge!(instrumentation::QUEUE_OCCUPANCY).set(queue_occupancy(&tx_clone));
                 });
             }
         }
     }
+
+    /// Accepts TCP connections, and reads messages from each of them in a
+    /// separate task. Those tasks are owned by the [JoinSet], so they get
+    /// aborted together with this loop on shutdown, and don't keep the
+    /// channel open. If there's a TLS config, connections have to go through
+    /// a TLS handshake first.
+    async fn accept_connections(
+        &self,
+        listener: &TcpListener,
+        tls_config: Option<&ReloadableTlsConfig>,
+    ) {
+        let mut connections = JoinSet::new();
+        loop {
+            // Clean up after closed connections, so the set doesn't grow
+            // forever.
+            while connections.try_join_next().is_some() {}
+
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    if !self.source_filter.accepts_connection(addr.ip()) {
+                        continue;
+                    }
+
+                    debug!("Accepted connection from {}", addr);
+                    let sender = self.received_sender.clone();
+                    let max_message_size = self.max_message_size;
+                    let source_filter = self.source_filter.clone();
+
+                    match tls_config.map(|config| config.acceptor()) {
+                        Some(acceptor) => connections.spawn(async move {
+                            let handshake = acceptor.accept(stream);
+                            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+                                Ok(Ok(stream)) => {
+                                    let reader = BufReader::new(stream);
+                                    receive_stream(
+                                        reader,
+                                        addr,
+                                        sender,
+                                        max_message_size,
+                                        source_filter,
+                                    )
+                                    .await
+                                }
+                                Ok(Err(err)) => {
+                                    warn!("TLS handshake with {} failed: {}", addr, err)
+                                }
+                                Err(_) => warn!("TLS handshake with {} timed out", addr),
+                            }
+                        }),
+                        None => connections.spawn(receive_stream(
+                            BufReader::new(stream),
+                            addr,
+                            sender,
+                            max_message_size,
+                            source_filter,
+                        )),
+                    };
+                }
+                Err(err) => {
+                    // This usually means we're out of file descriptors, so
+                    // give the existing connections some time to finish.
+                    warn!("Accepting connection failed: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+}
+
+/// Reads framed messages from a stream until it's closed. Unlike datagrams,
+/// which get dropped if the queue is full, this waits for the queue to have
+/// room again, so the sender gets slowed down instead of losing messages.
+async fn receive_stream<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    addr: SocketAddr,
+    sender: Sender<Datagram>,
+    max_message_size: usize,
+    source_filter: Arc<SourceFilter>,
+) {
+    loop {
+        let frame = match tcp_framing::read_frame(&mut reader, max_message_size).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                debug!("Connection from {} closed", addr);
+                return;
+            }
+            Err(err) => {
+                warn!("Closing connection from {}: {}", addr, err);
+                return;
+            }
+        };
+
+        let received_at = Utc::now();
+        counter!(instrumentation::DATAGRAMS_RECEIVED).increment(1);
+        counter!(instrumentation::BYTES_RECEIVED).increment(frame.len() as u64);
+        debug!("Received {} bytes from {}", frame.len(), addr);
+        if !source_filter.accepts_message(addr.ip()) {
+            continue;
+        }
+
+        let datagram = Datagram {
+            received_at,
+            source_addr: Some(addr),
+            payload: decode_payload(frame),
+        };
+        if sender.send(datagram).await.is_err() {
+            return;
+        }
+        gauge!(instrumentation::QUEUE_OCCUPANCY).set(queue_occupancy(&sender));
+    }
+}
+
+fn decode_payload(buf: Vec<u8>) -> Result<String, FromUtf8Error> {
+    let payload = String::from_utf8(buf);
+    match &payload {
+        Ok(payload) => trace!("Raw message: `{}`", payload),
+        Err(_) => counter!(instrumentation::UTF8_FAILURES).increment(1),
+    }
+
+    payload
 }
 
 struct QueueItemStorer {
     db_pool: PgPool,
     insert_batch_size: usize,
     insert_timeout: Duration,
-    insert_field_vecs: AccessLogColumnVecs,
-    receiver: Receiver<String>,
+    final_insert_timeout: Duration,
+    access_log_field_vecs: AccessLogColumnVecs,
+    mapped_field_vecs: MappedColumnVecs,
+    error_log_field_vecs: ErrorLogColumnVecs,
+    rejected_field_vecs: RejectedDatagramColumnVecs,
+    access_log_insert_sql: String,
+    error_log_insert_sql: String,
+    rejected_insert_sql: String,
+    field_mapping: FieldMapping,
+    enricher: Enricher,
+    sampling_rules: SamplingRules,
+    combined_log_tags: Vec<String>,
+    parsing_mode: ParsingMode,
+    store_rejected: bool,
+    auth_keys: AuthKeys,
+    retry_policy: RetryPolicy,
+    spool: Option<Spool>,
+    receiver: Receiver<Datagram>,
 }
 
 impl QueueItemStorer {
     pub fn new(
         db_pool: PgPool,
-        insert_batch_size: usize,
-        insert_timeout: u64,
-        receiver: Receiver<String>,
+        settings: &Settings,
+        field_mapping: FieldMapping,
+        enricher: Enricher,
+        sampling_rules: SamplingRules,
+        spool: Option<Spool>,
+        receiver: Receiver<Datagram>,
     ) -> Self {
+        let insert_batch_size = settings.insert_batch_size;
         Self {
             db_pool,
             insert_batch_size,
-            insert_timeout: Duration::from_millis(insert_timeout),
-            insert_field_vecs: AccessLogColumnVecs::with_capacity(insert_batch_size),
+            insert_timeout: Duration::from_millis(settings.insert_timeout),
+            // This leaves enough of SHUTDOWN_TIMEOUT to spool the batch, and
+            // the ones after it, if the insert times out.
+            final_insert_timeout: Duration::from_millis(settings.shutdown_timeout) / 4,
+            access_log_field_vecs: AccessLogColumnVecs::with_capacity(insert_batch_size),
+            mapped_field_vecs: MappedColumnVecs::with_capacity(&field_mapping, insert_batch_size),
+            error_log_field_vecs: ErrorLogColumnVecs::with_capacity(insert_batch_size),
+            rejected_field_vecs: RejectedDatagramColumnVecs::with_capacity(
+                if settings.store_rejected {
+                    insert_batch_size
+                } else {
+                    0
+                },
+            ),
+            // The mapped columns come last, since they're bound after all
+            // the [AccessLogColumnVecs] columns.
+            access_log_insert_sql: unnest_insert_sql(
+                "access_log",
+                AccessLogColumnVecs::COLUMNS
+                    .iter()
+                    .copied()
+                    .chain(field_mapping.columns()),
+            ),
+            error_log_insert_sql: unnest_insert_sql(
+                "error_log",
+                ErrorLogColumnVecs::COLUMNS.iter().copied(),
+            ),
+            rejected_insert_sql: unnest_insert_sql(
+                "rejected_datagram",
+                RejectedDatagramColumnVecs::COLUMNS.iter().copied(),
+            ),
+            field_mapping,
+            enricher,
+            sampling_rules,
+            combined_log_tags: settings.combined_log_tags.clone(),
+            parsing_mode: settings.parsing_mode.clone(),
+            store_rejected: settings.store_rejected,
+            auth_keys: AuthKeys::from_settings(settings),
+            retry_policy: RetryPolicy::from_settings(settings),
+            spool,
             receiver,
         }
     }
 
-    async fn store_batch(&mut self, batch: &Vec<String>) -> Result<PgQueryResult, sqlx::Error> {
-        self.insert_field_vecs.clear();
-        for line in batch {
-            if let Ok(entry) = Self::parse_datagram(line) {
-                self.insert_field_vecs.push(entry);
+    /// Parses the batch into the column vecs. This only happens once per
+    /// batch, so retries don't have to parse everything again. Spooled
+    /// batches went through this before they were spooled, so if `is_replay`
+    /// is set, nothing gets counted in the metrics or logged again.
+    fn fill_field_vecs(&mut self, batch: &[Datagram], is_replay: bool) {
+        self.access_log_field_vecs.clear();
+        self.mapped_field_vecs.clear();
+        self.error_log_field_vecs.clear();
+        self.rejected_field_vecs.clear();
+
+        // Entries with missing fields are only stored in lenient mode. To
+        // make misconfigured hosts visible, they're summarized per batch.
+        let mut missing_fields: BTreeMap<&'static str, usize> = BTreeMap::new();
+        let mut hosts_with_missing_fields: BTreeSet<String> = BTreeSet::new();
+
+        for datagram in batch {
+            match self.parse_datagram(datagram, is_replay) {
+                Ok(LogEntry::Access(mut entry)) => {
+                    // The rules can match on enriched fields, like the bot
+                    // flag, so enriching has to happen first.
+                    self.enricher.enrich(&mut entry);
+                    let payload = datagram.payload.as_deref().unwrap_or_default();
+                    match self.sampling_rules.evaluate(&entry, payload) {
+                        Decision::Keep { sample_rate } => entry.sample_rate = Some(sample_rate),
+                        Decision::Drop { rule } => {
+                            trace!("Dropped entry because of sampling rule `{}`", rule);
+                            if !is_replay {
+                                counter!(instrumentation::SAMPLING_DROPS, "rule" => rule)
+                                    .increment(1);
+                            }
+                            continue;
+                        }
+                    }
+
+                    if !entry.missing_fields.is_empty() && !is_replay {
+                        for field in &entry.missing_fields {
+                            *missing_fields.entry(field).or_default() += 1;
+                        }
+                        hosts_with_missing_fields.insert(entry.hostname.clone().unwrap_or_else(
+                            || {
+                                datagram
+                                    .source_addr
+                                    .map_or("unknown".to_owned(), |addr| addr.ip().to_string())
+                            },
+                        ));
+                    }
+
+                    self.mapped_field_vecs
+                        .push(std::mem::take(&mut entry.mapped_fields));
+                    self.access_log_field_vecs.push(entry);
+                }
+                Ok(LogEntry::Error(mut entry)) => {
+                    self.enricher.enrich_error_log(&mut entry);
+                    self.error_log_field_vecs.push(entry);
+                }
+                Err(err) => {
+                    debug!("Rejected datagram: {}", err);
+                    if !is_replay {
+                        if err.is::<AuthError>() {
+                            counter!(instrumentation::AUTH_FAILURES).increment(1);
+                        } else if datagram.payload.is_ok() {
+                            counter!(instrumentation::PARSE_FAILURES).increment(1);
+                        }
+                    }
+                    if self.store_rejected {
+                        self.rejected_field_vecs.push(datagram, err.to_string());
+                    }
+                }
             }
         }
 
-        // Note: If any columns are added, removed, renamed, reorderd, or
-        // otherwise touched, make sure to update [AccessLogColumnVecs].
-        let query = sqlx::query(
-            r#"
-            INSERT INTO access_log (
-                id, hostname, event_ts, server_name, server_port, client_addr, client_forwarded_for, client_referer,
-                client_ua, req_host, req_length, req_method, req_proto, req_scheme, req_uri, res_body_length,
-                res_duration, res_length, res_status, upstream_addr, upstream_bytes_received, upstream_bytes_sent,
-                upstream_cache_status, upstream_connect_time, upstream_host, upstream_response_length,
-                upstream_response_time, upstream_status
-            ) SELECT * FROM UNNEST(
-                $1::uuid[], $2::text[], $3::timestamptz[], $4::text[], $5::int4[], $6::text[], $7::text[], $8::text[],
-                $9::text[], $10::text[], $11::int8[], $12::text[], $13::text[], $14::text[], $15::text[], $16::int8[],
-                $17::float8[], $18::int8[], $19::int4[], $20::text[], $21::int8[], $22::int8[], $23::text[],
-                $24::float8[], $25::text[], $26::int8[], $27::float8[], $28::int4[]
-            )"#,
+        if !missing_fields.is_empty() {
+            let fields: Vec<String> = missing_fields
+                .iter()
+                .map(|(field, count)| format!("{} ({})", field, count))
+                .collect();
+            let hosts: Vec<String> = hosts_with_missing_fields.into_iter().collect();
+            warn!(
+                "Stored entries with missing fields: {}, from {}",
+                fields.join(", "),
+                hosts.join(", ")
+            );
+        }
+    }
+
+    /// Inserts everything in the column vecs. All tables are written in one
+    /// transaction, so a failed batch can be retried as a whole without
+    /// creating duplicates.
+    async fn insert_field_vecs(&self) -> Result<(), sqlx::Error> {
+        let mut inserted_rows = InsertedRows::new();
+        let mut tx = self.db_pool.begin().await?;
+        self.execute_inserts(&mut tx, &mut inserted_rows).await?;
+        tx.commit().await?;
+
+        record_inserted_rows(inserted_rows);
+        Ok(())
+    }
+
+    /// Runs the INSERT queries for all non-empty column vecs, and adds the
+    /// number of rows to `inserted_rows`. The caller is responsible for
+    /// wrapping this in a transaction.
+    async fn execute_inserts(
+        &self,
+        conn: &mut PgConnection,
+        inserted_rows: &mut InsertedRows,
+    ) -> Result<(), sqlx::Error> {
+        if !self.access_log_field_vecs.is_empty() {
+            let query = sqlx::query(&self.access_log_insert_sql);
+            let query = self.access_log_field_vecs.bind_all(query);
+            let query = self.mapped_field_vecs.bind_all(query);
+            *inserted_rows.entry("access_log").or_default() +=
+                execute_insert(conn, "access_log", query).await?;
+        }
+
+        if !self.error_log_field_vecs.is_empty() {
+            let query = sqlx::query(&self.error_log_insert_sql);
+            let query = self.error_log_field_vecs.bind_all(query);
+            *inserted_rows.entry("error_log").or_default() +=
+                execute_insert(conn, "error_log", query).await?;
+        }
+
+        if !self.rejected_field_vecs.is_empty() {
+            let query = sqlx::query(&self.rejected_insert_sql);
+            let query = self.rejected_field_vecs.bind_all(query);
+            *inserted_rows.entry("rejected_datagram").or_default() +=
+                execute_insert(conn, "rejected_datagram", query).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn store_batch(&mut self, batch: &[Datagram]) -> Result<(), sqlx::Error> {
+        self.fill_field_vecs(batch, false);
+
+        let started_at = Instant::now();
+        let mut attempt = 0;
+        loop {
+            let err = match self.insert_field_vecs().await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+
+            // If shutdown started in the meantime, there's no time left for
+            // retries, so the batch gets spooled instead.
+            let Some(delay) = self
+                .retry_policy
+                .next_delay(attempt, started_at.elapsed(), &err)
+                .filter(|_| !self.receiver.is_closed())
+            else {
+                return Err(err);
+            };
+
+            warn!(
+                "Inserting into database failed, retrying in {:?}: {}",
+                delay, err
+            );
+            counter!(instrumentation::INSERT_RETRIES).increment(1);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Stores a batch as part of the final flush on shutdown. There's no time
+    /// for retries then, and if the database is unreachable, just getting a
+    /// connection can take longer than SHUTDOWN_TIMEOUT. So there's only one
+    /// attempt with a short timeout, and if that fails, the batch can be
+    /// spooled right away.
+    async fn store_final_batch(&mut self, batch: &[Datagram]) -> Result<(), sqlx::Error> {
+        self.fill_field_vecs(batch, false);
+
+        match tokio::time::timeout(self.final_insert_timeout, self.insert_field_vecs()).await {
+            Ok(result) => result,
+            Err(_) => Err(sqlx::Error::PoolTimedOut),
+        }
+    }
+
+    /// Writes a batch that couldn't be inserted into the spool, if there is
+    /// one. Batches that failed for reasons that won't go away by waiting, like
+    /// the database refusing the data, would just fail again on every replay,
+    /// so those get dropped. Returns whether the batch ended up in the spool.
+    async fn spool_batch(&mut self, batch: &[Datagram], err: sqlx::Error) -> bool {
+        let spool = match &mut self.spool {
+            Some(spool) if retry_policy::is_transient(&err) => spool,
+            _ => {
+                error!("Inserting into database failed: {:?}", err);
+                return false;
+            }
+        };
+
+        warn!(
+            "Inserting into database failed, spooling {} datagrams: {}",
+            batch.len(),
+            err
         );
+        let spooled = match spool.append(batch).await {
+            Ok(evicted) => {
+                counter!(instrumentation::SPOOLED_DATAGRAMS).increment(batch.len() as u64);
+                counter!(instrumentation::SPOOL_EVICTED_SEGMENTS).increment(evicted as u64);
+                true
+            }
+            Err(err) => {
+                error!("Writing batch into the spool failed: {:?}", err);
+                false
+            }
+        };
+        gauge!(instrumentation::SPOOL_SIZE).set(spool.total_size() as f64);
+
+        spooled
+    }
+
+    /// Replays the oldest spool segment, if there is one.
+    async fn replay_spool(&mut self) {
+        let Some(mut spool) = self.spool.take() else {
+            return;
+        };
+
+        if let Err(err) = self.replay_oldest_segment(&mut spool).await {
+            error!("Replaying the spool failed: {:?}", err);
+        }
+        gauge!(instrumentation::SPOOL_SIZE).set(spool.total_size() as f64);
+
+        self.spool = Some(spool);
+    }
+
+    async fn replay_oldest_segment(&mut self, spool: &mut Spool) -> Result<()> {
+        let Some(segment) = spool.oldest_segment().await? else {
+            return Ok(());
+        };
+        let datagrams = Spool::read_segment(&segment).await?;
 
-        self.insert_field_vecs
-            .bind_all(query)
-            .execute(&self.db_pool)
-            .await
+        match self.insert_replayed(&datagrams).await {
+            Ok(()) => {
+                spool.remove_segment(&segment).await?;
+                counter!(instrumentation::SPOOL_REPLAYED_DATAGRAMS)
+                    .increment(datagrams.len() as u64);
+                info!("Replayed {} spooled datagrams", datagrams.len());
+            }
+            Err(err) if retry_policy::is_transient(&err) => {
+                debug!("Database still unavailable, not replaying spool: {}", err);
+            }
+            Err(err) => {
+                error!(
+                    "Replaying {} failed, setting it aside: {:?}",
+                    segment.display(),
+                    err
+                );
+                spool.mark_segment_failed(&segment).await?;
+            }
+        }
+
+        Ok(())
     }
 
-    pub async fn run(&mut self) {
-        let mut batch: Vec<String> = Vec::with_capacity(self.insert_batch_size);
+    /// Inserts a whole spool segment in one transaction, so it either ends up
+    /// in the database completely, or can be replayed again later without
+    /// creating duplicates.
+    async fn insert_replayed(&mut self, datagrams: &[Datagram]) -> Result<(), sqlx::Error> {
+        let mut inserted_rows = InsertedRows::new();
+        let mut tx = self.db_pool.begin().await?;
+        for chunk in datagrams.chunks(self.insert_batch_size) {
+            self.fill_field_vecs(chunk, true);
+            self.execute_inserts(&mut tx, &mut inserted_rows).await?;
+        }
+        tx.commit().await?;
+
+        record_inserted_rows(inserted_rows);
+        Ok(())
+    }
+
+    /// Collects datagrams into batches and stores them, until the channel is
+    /// closed and empty. Returns an error if any batch that was stored after
+    /// the channel got closed could neither be inserted nor spooled.
+    pub async fn run(&mut self) -> Result<()> {
+        let mut batch: Vec<Datagram> = Vec::with_capacity(self.insert_batch_size);
+        let mut flush_failed = false;
+        let mut database_unavailable = false;
+        let mut spool_replay_interval = tokio::time::interval(SPOOL_REPLAY_INTERVAL);
+        spool_replay_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         loop {
-            let received = self
-                .receiver
-                .recv_many(&mut batch, self.insert_batch_size)
-                .await;
+            let received = tokio::select! {
+                received = self.receiver.recv_many(&mut batch, self.insert_batch_size) => Some(received),
+                // Replays can take a while if the database isn't responding,
+                // so they stop once shutdown starts.
+                _ = spool_replay_interval.tick(), if self.spool.is_some() && !self.receiver.is_closed() => None,
+            };
+            let Some(received) = received else {
+                self.replay_spool().await;
+                continue;
+            };
             if received < 1 {
-                warn!("Channel closed, exiting storer loop...");
-                return;
+                info!("Channel closed, exiting storer loop...");
+                return if flush_failed {
+                    Err(Error::msg("storing the pending datagrams failed"))
+                } else {
+                    Ok(())
+                };
             }
 
             let mut batch_size = batch.len();
@@ -184,15 +785,45 @@ impl QueueItemStorer {
                 let _ = tokio::time::timeout(self.insert_timeout, async {
                     while batch_size < self.insert_batch_size {
                         let remaining = self.insert_batch_size - batch_size;
-                        let _ = self.receiver.recv_many(&mut batch, remaining).await;
+                        // This only returns 0 if the channel is closed, so
+                        // there's nothing more to wait for.
+                        if self.receiver.recv_many(&mut batch, remaining).await == 0 {
+                            break;
+                        }
                         batch_size = batch.len();
                     }
                 })
                 .await;
             }
 
-            if let Err(err) = self.store_batch(&batch).await {
-                error!("Inserting into database failed: {:?}", err);
+            gauge!(instrumentation::QUEUE_OCCUPANCY).set(self.receiver.len() as f64);
+            histogram!(instrumentation::BATCH_SIZE).record(batch_size as f64);
+            // The channel only gets closed on shutdown, so everything that's
+            // stored after that is part of the final flush.
+            let is_final_flush = self.receiver.is_closed();
+            let result = if !is_final_flush {
+                self.store_batch(&batch).await
+            } else if database_unavailable {
+                // An earlier batch of the final flush already timed out, so
+                // this one would as well.
+                Err(sqlx::Error::PoolTimedOut)
+            } else {
+                self.store_final_batch(&batch).await
+            };
+
+            let stored = match result {
+                Ok(()) => true,
+                Err(err) => {
+                    counter!(instrumentation::INSERT_FAILURES).increment(1);
+                    if is_final_flush && retry_policy::is_transient(&err) {
+                        database_unavailable = true;
+                    }
+                    self.spool_batch(&batch, err).await
+                }
+            };
+
+            if !stored && is_final_flush {
+                flush_failed = true;
             }
 
             info!("Processed batch of {} entries", batch_size);
@@ -200,13 +831,130 @@ impl QueueItemStorer {
         }
     }
 
-    fn parse_datagram(datagram: &str) -> Result<AccessLogEntry> {
-        // at the moment, I'm completely ignoring everything provided by syslog
-        // except the message. I could skip the syslog parsing, and just look for
-        // the opening {, then read from there.
-        // However, in the future, I might expand this with the ability to handle
-        // error_log as well... so let's keep this for now.
-        let syslog = syslog_loose::parse_message(datagram, syslog_loose::Variant::Either);
-        serde_json::from_str(syslog.msg).map_err(Error::msg)
+    fn parse_datagram(&self, datagram: &Datagram, is_replay: bool) -> Result<LogEntry> {
+        let payload = datagram
+            .payload
+            .as_ref()
+            .map_err(|err| Error::msg(err.utf8_error()))?;
+        let syslog = syslog_loose::parse_message(payload, syslog_loose::Variant::Either);
+        let metadata = SyslogMetadata::new(&syslog, datagram.source_addr.map(|a| a.ip()));
+
+        // nginx' access_log is configured to send JSON, so anything that looks
+        // like JSON is an access_log entry. Everything else is either an
+        // access_log entry in the combined format, or coming from the
+        // error_log. Unless the tag says which one it is, the combined format
+        // is tried first, since it's much stricter.
+        if syslog.msg.starts_with('{') {
+            // This goes through a [serde_json::Value], so the mapped fields
+            // can be read from the same document without parsing it twice.
+            let json = serde_json::from_str::<serde_json::Value>(syslog.msg).map_err(Error::msg)?;
+            let mut entry = AccessLogEntry::from_json(&json)?;
+            self.auth_keys.verify(&syslog, entry.auth.as_deref())?;
+
+            entry.missing_fields = AccessLogEntry::missing_fields(&json);
+            if !is_replay {
+                for field in &entry.missing_fields {
+                    counter!(instrumentation::MISSING_FIELDS, "field" => *field).increment(1);
+                }
+            }
+            if !entry.missing_fields.is_empty() {
+                if self.parsing_mode == ParsingMode::Strict {
+                    bail!("missing field `{}`", entry.missing_fields.join("`, `"));
+                }
+                if entry.missing_fields.contains(&"ts") {
+                    entry.ts = datagram.received_at;
+                }
+            }
+
+            let (mapped_fields, invalid_fields) = self.field_mapping.extract(&json);
+            entry.mapped_fields = mapped_fields;
+            if !is_replay {
+                for field in invalid_fields {
+                    counter!(instrumentation::INVALID_MAPPED_FIELDS, "field" => field.to_owned())
+                        .increment(1);
+                }
+            }
+            entry.collect_extra();
+            self.field_mapping.remove_mapped(&mut entry.extra);
+            entry.syslog = metadata;
+            return Ok(entry.into());
+        }
+
+        self.auth_keys.verify(&syslog, None)?;
+        let is_combined_tag = metadata
+            .tag
+            .as_ref()
+            .is_some_and(|tag| self.combined_log_tags.contains(tag));
+        let combined = match AccessLogEntry::from_combined(&syslog) {
+            Err(err) if is_combined_tag => return Err(err),
+            combined => combined.ok(),
+        };
+
+        match combined {
+            Some(mut entry) => {
+                // The combined format has none of the mapped fields, but
+                // there has to be a value for each of their columns.
+                entry.mapped_fields = self.field_mapping.extract(&serde_json::Value::Null).0;
+                entry.syslog = metadata;
+                Ok(entry.into())
+            }
+            None => {
+                let mut entry = ErrorLogEntry::from_syslog(&syslog, datagram.received_at)?;
+                entry.syslog = metadata;
+                Ok(entry.into())
+            }
+        }
+    }
+}
+
+/// The number of rows inserted per table within one transaction.
+type InsertedRows = BTreeMap<&'static str, u64>;
+
+/// Runs one of the batched INSERT queries, keeps track of how long that took,
+/// and returns the number of inserted rows.
+async fn execute_insert(
+    conn: &mut PgConnection,
+    table: &'static str,
+    query: Query<'_, Postgres, PgArguments>,
+) -> Result<u64, sqlx::Error> {
+    let started_at = Instant::now();
+    let result = query.execute(conn).await?;
+    histogram!(instrumentation::INSERT_DURATION, "table" => table).record(started_at.elapsed());
+
+    Ok(result.rows_affected())
+}
+
+/// Rows only end up in the database once the transaction is committed, so
+/// this has to wait until then. Otherwise, batches that get rolled back and
+/// retried would be counted more than once.
+fn record_inserted_rows(inserted_rows: InsertedRows) {
+    for (table, rows) in inserted_rows {
+        counter!(instrumentation::ROWS_INSERTED, "table" => table).increment(rows);
+    }
+}
+
+fn queue_occupancy(sender: &Sender<Datagram>) -> f64 {
+    (sender.max_capacity() - sender.capacity()) as f64
+}
+
+/// Deletes rows from `rejected_datagram` that are older than the configured
+/// retention. TimescaleDB's retention policies would do that as well, but
+/// this has to work without TimescaleDB, and it's configurable this way.
+async fn prune_rejected_datagrams(db_pool: PgPool, retention_days: u16) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+
+        let result = sqlx::query(
+            "DELETE FROM rejected_datagram WHERE received_ts < now() - make_interval(days => $1)",
+        )
+        .bind(i32::from(retention_days))
+        .execute(&db_pool)
+        .await;
+
+        match result {
+            Ok(result) => debug!("Pruned {} rejected datagrams", result.rows_affected()),
+            Err(err) => error!("Pruning rejected datagrams failed: {:?}", err),
+        }
     }
 }
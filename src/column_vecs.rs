@@ -0,0 +1,182 @@
+/// This macro just exists to reduce pain with defining the functions that are
+/// just boring calls to something where the only difference is the field name.
+///
+/// Every field is listed together with its column and the column's SQL type,
+/// like `ts => event_ts::timestamptz`, so the INSERT query can be generated
+/// from that instead of having to be kept in sync by hand. If the column has a
+/// different type than the vec's values, that goes last, like
+/// `attempts => attempts::text as "int4[]"`.
+macro_rules! column_vecs_impl {
+    (
+        $name:ident {
+            $($field:ident => $column:ident :: $sql_type:ident $(as $cast:literal)?),* $(,)?
+        }
+    ) => {
+        impl $name {
+            /// All columns with their SQL types, in the order they get bound
+            /// in [Self::bind_all].
+            pub const COLUMNS: &'static [$crate::column_vecs::Column<'static>] = &[
+                $(
+                    $crate::column_vecs::Column {
+                        name: stringify!($column),
+                        sql_type: stringify!($sql_type),
+                        cast: $crate::column_vecs::column_cast!($($cast)?),
+                    },
+                )*
+            ];
+
+            pub fn with_capacity(capacity: usize) -> Self {
+                Self {
+                    $(
+                        $field: Vec::with_capacity(capacity),
+                    )*
+                }
+            }
+
+            pub fn clear(&mut self) {
+                $(
+                    self.$field.clear();
+                )*
+            }
+
+            pub fn is_empty(&self) -> bool {
+                // All tables have an id column, and all vecs have the same
+                // length, so checking one of them is enough.
+                self.id.is_empty()
+            }
+
+            pub fn bind_all<'q>(
+                &'q self,
+                mut query: Query<'q, Postgres, PgArguments>,
+            ) -> Query<'q, Postgres, PgArguments> {
+                $(
+                    query = query.bind(&self.$field);
+                )*
+                query
+            }
+        }
+    }
+}
+
+/// Turns the optional `as "type"` of a column into an [Option].
+macro_rules! column_cast {
+    () => {
+        None
+    };
+    ($cast:literal) => {
+        Some($cast)
+    };
+}
+
+/// Likewise, this is just a glorified string replace to make assigning values
+/// in the push() fn a bit less verbose.
+macro_rules! column_vecs_push_body {
+    (
+        $self:ident, $entry:ident, {
+            $($field:ident: $expr:expr),* $(,)?
+        }
+    ) => {
+        $(
+            $self.$field.push($expr);
+        )*
+    }
+}
+
+/// One column of a table, with everything the INSERT query needs to know
+/// about it.
+#[derive(Clone, Copy, Debug)]
+pub struct Column<'a> {
+    pub name: &'a str,
+
+    /// The type the column's values are bound as.
+    pub sql_type: &'a str,
+
+    /// If set, the values get cast into this type while inserting. This is
+    /// needed for array columns, since UNNEST can't return arrays from a
+    /// multidimensional array. They're bound as array literals instead.
+    pub cast: Option<&'a str>,
+}
+
+/// Builds the `INSERT INTO ... SELECT ... FROM UNNEST` query for the given
+/// columns. Each column gets bound as one array parameter, in the same order.
+pub fn unnest_insert_sql<'a>(table: &str, columns: impl IntoIterator<Item = Column<'a>>) -> String {
+    let mut names = vec![];
+    let mut values = vec![];
+    let mut params = vec![];
+    for (i, column) in columns.into_iter().enumerate() {
+        let name = format!("\"{}\"", column.name);
+        values.push(match column.cast {
+            Some(cast) => format!("{}::{}", name, cast),
+            None => name.clone(),
+        });
+        params.push(format!("${}::{}[]", i + 1, column.sql_type));
+        names.push(name);
+    }
+
+    format!(
+        "INSERT INTO {table} ({names}) SELECT {values} FROM UNNEST({params}) AS batch({names})",
+        table = table,
+        names = names.join(", "),
+        values = values.join(", "),
+        params = params.join(", "),
+    )
+}
+
+/// Turns a list of values into a PostgreSQL array literal, like
+/// `{"a",NULL}`, for columns that get cast into an array.
+pub fn array_literal<T: std::fmt::Display>(values: &[Option<T>]) -> String {
+    let elements: Vec<String> = values
+        .iter()
+        .map(|value| match value {
+            Some(value) => format!(
+                "\"{}\"",
+                value.to_string().replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+            None => "NULL".to_owned(),
+        })
+        .collect();
+
+    format!("{{{}}}", elements.join(","))
+}
+
+pub(crate) use column_cast;
+pub(crate) use column_vecs_impl;
+pub(crate) use column_vecs_push_body;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_unnest_insert_sql() {
+        let columns = [
+            Column {
+                name: "id",
+                sql_type: "uuid",
+                cast: None,
+            },
+            Column {
+                name: "attempts",
+                sql_type: "text",
+                cast: Some("int4[]"),
+            },
+        ];
+        assert_eq!(
+            r#"INSERT INTO meow ("id", "attempts") SELECT "id", "attempts"::int4[] FROM UNNEST($1::uuid[], $2::text[]) AS batch("id", "attempts")"#,
+            unnest_insert_sql("meow", columns)
+        );
+    }
+
+    #[test]
+    fn builds_array_literals() {
+        assert_eq!("{}", array_literal::<i32>(&[]));
+        assert_eq!(
+            r#"{"502",NULL,"200"}"#,
+            array_literal(&[Some(502), None, Some(200)])
+        );
+        assert_eq!(
+            r#"{"a\"b","c\\d"}"#,
+            array_literal(&[Some(r#"a"b"#), Some(r#"c\d"#)])
+        );
+    }
+}
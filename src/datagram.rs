@@ -0,0 +1,15 @@
+use std::{net::SocketAddr, string::FromUtf8Error};
+
+use chrono::{DateTime, Utc};
+
+/// A single syslog message, exactly as it was received, plus the things only
+/// the receiving socket knows about it.
+#[derive(Debug)]
+pub struct Datagram {
+    pub received_at: DateTime<Utc>,
+    pub source_addr: Option<SocketAddr>,
+
+    /// Datagrams that aren't valid UTF-8 can't be parsed, but they're kept
+    /// around so they can end up in the `rejected_datagram` table.
+    pub payload: Result<String, FromUtf8Error>,
+}
@@ -0,0 +1,204 @@
+mod anonymizer;
+mod geoip;
+mod real_ip;
+mod referer;
+mod request_uri;
+mod user_agent;
+
+use std::net::IpAddr;
+
+use anyhow::Result;
+
+use crate::{
+    parsers::{AccessLogEntry, ErrorLogEntry},
+    settings::Settings,
+};
+
+pub use anonymizer::Anonymizer;
+pub use geoip::{GeoIp, Location};
+pub use real_ip::{RealIp, parse_forwarded_for};
+pub use referer::Referer;
+pub use request_uri::{REDACTED, redact_query, redact_uri, split_uri};
+pub use user_agent::{UserAgent, UserAgentParser};
+
+/// Values that aren't part of the log line itself, but are derived from it
+/// before it gets stored.
+#[derive(Debug, Default)]
+pub struct Enrichment {
+    /// The addresses in `client.forwarded_for`, in the order they were sent.
+    /// Entries that aren't valid addresses are kept as `None`.
+    pub forwarded_for_addrs: Vec<Option<IpAddr>>,
+
+    /// The client's address as nginx' realip module would see it, with
+    /// TRUSTED_PROXIES as `set_real_ip_from`.
+    pub real_addr: Option<IpAddr>,
+
+    /// `req.uri` without the query string.
+    pub req_path: Option<String>,
+
+    /// The query string of `req.uri`, without the `?`, and with the values of
+    /// REDACT_QUERY_PARAMS redacted.
+    pub req_query: Option<String>,
+
+    /// Only set if PARSE_USER_AGENTS is enabled.
+    pub user_agent: UserAgent,
+
+    /// Where [Self::real_addr] is located. Only set if GEOIP_CITY_DB or
+    /// GEOIP_ASN_DB is set.
+    pub location: Location,
+
+    /// The parts of `client.referer`.
+    pub referer: Referer,
+}
+
+/// Derives everything in [Enrichment] for each `access_log` entry. This runs
+/// after parsing, so it works the same for all log formats.
+pub struct Enricher {
+    real_ip: RealIp,
+
+    /// REDACT_QUERY_PARAMS, in lowercase.
+    redact_query_params: Vec<String>,
+
+    /// OWN_DOMAINS, in lowercase.
+    own_domains: Vec<String>,
+
+    user_agent_parser: Option<UserAgentParser>,
+
+    geoip: Option<GeoIp>,
+
+    anonymizer: Anonymizer,
+}
+
+impl Enricher {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let user_agent_parser = if settings.parse_user_agents {
+            Some(UserAgentParser::load(
+                settings.ua_regexes_file.as_deref(),
+                settings.ua_cache_size,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            real_ip: RealIp {
+                trusted_proxies: settings.trusted_proxies.clone(),
+                recursive: settings.real_ip_recursive,
+            },
+            redact_query_params: settings
+                .redact_query_params
+                .iter()
+                .map(|param| param.to_lowercase())
+                .collect(),
+            own_domains: settings
+                .own_domains
+                .iter()
+                .map(|domain| domain.trim().trim_end_matches('.').to_lowercase())
+                .collect(),
+            user_agent_parser,
+            geoip: GeoIp::from_settings(settings)?,
+            anonymizer: Anonymizer::from_settings(settings)?,
+        })
+    }
+
+    pub fn enrich(&mut self, entry: &mut AccessLogEntry) {
+        let forwarded_for_addrs = entry
+            .client
+            .forwarded_for
+            .as_deref()
+            .map(parse_forwarded_for)
+            .unwrap_or_default();
+
+        entry.enrichment.real_addr = self
+            .real_ip
+            .real_addr(entry.client.addr, &forwarded_for_addrs);
+        entry.enrichment.forwarded_for_addrs = forwarded_for_addrs;
+
+        if let (Some(geoip), Some(ip)) = (&mut self.geoip, entry.enrichment.real_addr) {
+            entry.enrichment.location = geoip.lookup(ip);
+        }
+
+        if let Some(uri) = &mut entry.req.uri {
+            let (path, query) = split_uri(uri);
+            let path = path.to_owned();
+            let query = query.map(|query| redact_query(query, &self.redact_query_params));
+
+            // `req_uri` contains the query string as well, so it has to be
+            // redacted in there, too.
+            if !self.redact_query_params.is_empty()
+                && let Some(query) = &query
+            {
+                *uri = format!("{}?{}", path, query);
+            }
+
+            entry.enrichment.req_path = Some(path);
+            entry.enrichment.req_query = query;
+        }
+
+        if let (Some(parser), Some(ua)) = (&mut self.user_agent_parser, &entry.client.ua) {
+            entry.enrichment.user_agent = parser.parse(ua);
+        }
+
+        if let Some(referer) = &mut entry.client.referer {
+            // The referer is the URL of another page, which can contain the
+            // same parameters.
+            if !self.redact_query_params.is_empty() {
+                *referer = redact_uri(referer, &self.redact_query_params);
+            }
+
+            let mut referer = Referer::parse(referer);
+            referer.classify(entry.req.host.as_deref(), &self.own_domains);
+            entry.enrichment.referer = referer;
+        }
+
+        self.anonymize(entry);
+    }
+
+    /// Addresses in `error_log` entries are anonymized as well, and
+    /// REDACT_QUERY_PARAMS get redacted in the request line and the
+    /// referrer. Only those fields are affected, not the message itself.
+    pub fn enrich_error_log(&self, entry: &mut ErrorLogEntry) {
+        if !self.redact_query_params.is_empty() {
+            if let Some(request) = &mut entry.request {
+                *request = request
+                    .split(' ')
+                    .map(|part| redact_uri(part, &self.redact_query_params))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+            }
+            if let Some(subrequest) = &mut entry.subrequest {
+                *subrequest = redact_uri(subrequest, &self.redact_query_params);
+            }
+            if let Some(referrer) = &mut entry.referrer {
+                *referrer = redact_uri(referrer, &self.redact_query_params);
+            }
+        }
+
+        if let Some(client) = &mut entry.client {
+            *client = self
+                .anonymizer
+                .anonymize_forwarded_for(client, entry.ts.date_naive());
+        }
+    }
+
+    /// This runs last, since everything else needs the real addresses.
+    fn anonymize(&self, entry: &mut AccessLogEntry) {
+        if !self.anonymizer.is_enabled() {
+            return;
+        }
+
+        let date = entry.ts.date_naive();
+        let anonymize = |ip: &mut IpAddr| *ip = self.anonymizer.anonymize(*ip, date);
+
+        entry
+            .client
+            .addr
+            .iter_mut()
+            .chain(&mut entry.enrichment.real_addr)
+            .chain(entry.enrichment.forwarded_for_addrs.iter_mut().flatten())
+            .for_each(anonymize);
+        if let Some(forwarded_for) = &mut entry.client.forwarded_for {
+            *forwarded_for = self.anonymizer.anonymize_forwarded_for(forwarded_for, date);
+        }
+    }
+}
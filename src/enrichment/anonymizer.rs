@@ -0,0 +1,249 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+};
+
+use anyhow::{Result, bail};
+use chrono::{NaiveDate, Utc};
+use hmac::{Hmac, Mac};
+use ipnet::{Ipv4Net, Ipv6Net};
+use sha2::Sha256;
+
+use crate::settings::{IpAnonymization, Settings};
+
+/// How much of an address is kept with IP_ANONYMIZATION=truncate.
+const IPV4_PREFIX_LEN: u8 = 24;
+const IPV6_PREFIX_LEN: u8 = 48;
+
+/// Pseudonyms are addresses in `fd04::/16` for IPv4 addresses, and in
+/// `fd06::/16` for IPv6 addresses. Both are in the unique local range, so
+/// they can't be confused with public addresses.
+const PSEUDONYM_PREFIX_V4: [u8; 2] = [0xfd, 0x04];
+const PSEUDONYM_PREFIX_V6: [u8; 2] = [0xfd, 0x06];
+
+/// Removes personal data from client addresses before they're stored.
+pub enum Anonymizer {
+    None,
+    Truncate,
+    Hmac {
+        key: Vec<u8>,
+        daily_salt: Mutex<DailySalt>,
+    },
+}
+
+/// A random salt for the pseudonyms of one day. It's only ever kept in
+/// memory, and replaced once the day is over, so nobody can compute the
+/// pseudonyms of past days again, not even with IP_ANONYMIZATION_KEY.
+pub struct DailySalt {
+    date: NaiveDate,
+    salt: [u8; 32],
+}
+
+impl DailySalt {
+    fn new(date: NaiveDate) -> Self {
+        Self {
+            date,
+            salt: random_salt(),
+        }
+    }
+
+    /// Returns the salt for requests on `date`. Salts of past days are gone,
+    /// so requests from them, like ones that arrive late, or get replayed
+    /// from the spool after midnight, get a salt of their own, which makes
+    /// their pseudonyms impossible to link to anything.
+    fn for_date(&mut self, date: NaiveDate, today: NaiveDate) -> [u8; 32] {
+        if self.date != today {
+            *self = Self::new(today);
+        }
+
+        if date == self.date {
+            self.salt
+        } else {
+            random_salt()
+        }
+    }
+}
+
+fn random_salt() -> [u8; 32] {
+    let mut salt = [0; 32];
+    getrandom::fill(&mut salt).expect("the OS to provide random numbers");
+    salt
+}
+
+impl Anonymizer {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        Ok(match settings.ip_anonymization {
+            IpAnonymization::None => Self::None,
+            IpAnonymization::Truncate => Self::Truncate,
+            IpAnonymization::Hmac => match &settings.ip_anonymization_key {
+                Some(key) if !key.is_empty() => Self::hmac(key.as_bytes()),
+                _ => bail!("IP_ANONYMIZATION_KEY is required for IP_ANONYMIZATION=hmac"),
+            },
+        })
+    }
+
+    fn hmac(key: &[u8]) -> Self {
+        Self::Hmac {
+            key: key.to_vec(),
+            daily_salt: Mutex::new(DailySalt::new(Utc::now().date_naive())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, Self::None)
+    }
+
+    /// `date` is the day the request happened on. Pseudonyms are only the
+    /// same for requests on the same day, and only while it's still that day.
+    pub fn anonymize(&self, ip: IpAddr, date: NaiveDate) -> IpAddr {
+        self.anonymize_on(ip, date, Utc::now().date_naive())
+    }
+
+    fn anonymize_on(&self, ip: IpAddr, date: NaiveDate, today: NaiveDate) -> IpAddr {
+        match self {
+            Self::None => ip,
+            Self::Truncate => match ip {
+                IpAddr::V4(ip) => IpAddr::V4(
+                    Ipv4Net::new(ip, IPV4_PREFIX_LEN)
+                        .expect("prefix length to be valid")
+                        .network(),
+                ),
+                IpAddr::V6(ip) => IpAddr::V6(
+                    Ipv6Net::new(ip, IPV6_PREFIX_LEN)
+                        .expect("prefix length to be valid")
+                        .network(),
+                ),
+            },
+            Self::Hmac { key, daily_salt } => {
+                let salt = daily_salt
+                    .lock()
+                    .expect("daily salt lock to not be poisoned")
+                    .for_date(date, today);
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(key).expect("HMAC to accept keys of any length");
+                mac.update(&salt);
+                let daily_key = mac.finalize().into_bytes();
+
+                let mut mac = Hmac::<Sha256>::new_from_slice(&daily_key)
+                    .expect("HMAC to accept keys of any length");
+                let prefix = match ip {
+                    IpAddr::V4(ip) => {
+                        mac.update(&ip.octets());
+                        PSEUDONYM_PREFIX_V4
+                    }
+                    IpAddr::V6(ip) => {
+                        mac.update(&ip.octets());
+                        PSEUDONYM_PREFIX_V6
+                    }
+                };
+
+                let mut octets = [0; 16];
+                octets[..2].copy_from_slice(&prefix);
+                octets[2..].copy_from_slice(&mac.finalize().into_bytes()[..14]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        }
+    }
+
+    /// Anonymizes every address in an `X-Forwarded-For` header. Entries that
+    /// aren't addresses are kept as they are, and ports are removed.
+    pub fn anonymize_forwarded_for(&self, forwarded_for: &str, date: NaiveDate) -> String {
+        forwarded_for
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpAddr>()
+                    .or_else(|_| entry.parse::<SocketAddr>().map(|addr| addr.ip()))
+                    .map_or(entry.to_owned(), |ip| {
+                        self.anonymize(ip.to_canonical(), date).to_string()
+                    })
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, day).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn truncates_addresses() {
+        let anonymizer = Anonymizer::Truncate;
+        assert_eq!(
+            ip("192.0.2.0"),
+            anonymizer.anonymize(ip("192.0.2.123"), date(1))
+        );
+        assert_eq!(
+            ip("2001:db8:1::"),
+            anonymizer.anonymize(ip("2001:db8:1:2:3:4:5:6"), date(1))
+        );
+    }
+
+    #[test]
+    fn replaces_addresses_with_daily_pseudonyms() {
+        let anonymizer = Anonymizer::hmac(b"meow");
+
+        let v4 = anonymizer.anonymize_on(ip("192.0.2.1"), date(1), date(1));
+        let v6 = anonymizer.anonymize_on(ip("2001:db8::1"), date(1), date(1));
+        assert!(v4.to_string().starts_with("fd04:"));
+        assert!(v6.to_string().starts_with("fd06:"));
+
+        assert_eq!(
+            v4,
+            anonymizer.anonymize_on(ip("192.0.2.1"), date(1), date(1))
+        );
+        assert_ne!(
+            v4,
+            anonymizer.anonymize_on(ip("192.0.2.2"), date(1), date(1))
+        );
+
+        // The same key doesn't lead to the same pseudonyms, since the salt is
+        // random.
+        let same_key = Anonymizer::hmac(b"meow");
+        assert_ne!(v4, same_key.anonymize_on(ip("192.0.2.1"), date(1), date(1)));
+    }
+
+    #[test]
+    fn forgets_salts_of_past_days() {
+        let anonymizer = Anonymizer::hmac(b"meow");
+        let v4 = anonymizer.anonymize_on(ip("192.0.2.1"), date(1), date(1));
+
+        let next_day = anonymizer.anonymize_on(ip("192.0.2.1"), date(2), date(2));
+        assert_ne!(v4, next_day);
+        assert_eq!(
+            next_day,
+            anonymizer.anonymize_on(ip("192.0.2.1"), date(2), date(2))
+        );
+
+        let late = anonymizer.anonymize_on(ip("192.0.2.1"), date(1), date(2));
+        assert!(late.to_string().starts_with("fd04:"));
+        assert_ne!(v4, late);
+        assert_ne!(next_day, late);
+        assert_ne!(
+            late,
+            anonymizer.anonymize_on(ip("192.0.2.1"), date(1), date(2))
+        );
+    }
+
+    #[test]
+    fn anonymizes_forwarded_for() {
+        assert_eq!(
+            "203.0.113.0, unknown, 2001:db8::, 198.51.100.0",
+            Anonymizer::Truncate.anonymize_forwarded_for(
+                "203.0.113.7, unknown,[2001:db8::1]:443, 198.51.100.1:1234",
+                date(1)
+            )
+        );
+    }
+}
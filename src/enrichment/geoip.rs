@@ -0,0 +1,345 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use maxminddb::{MaxMindDBError, Reader, geoip2};
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+use crate::settings::Settings;
+
+/// How often the database files are checked for changes. Tools like
+/// `geoipupdate` replace the files in place, and this picks those up without
+/// a restart.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The language used for region and city names.
+const NAME_LANGUAGE: &str = "en";
+
+/// Where a client address is located, and which network it belongs to.
+/// Everything is `None` if the address isn't in the databases, or if there
+/// are no databases configured.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    pub country_code: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<i64>,
+    pub as_org: Option<String>,
+}
+
+/// Looks up client addresses in MaxMind DB files, like the GeoLite2
+/// databases. All lookups are local, so there's no network access involved.
+pub struct GeoIp {
+    city: Option<ReloadableDatabase>,
+    asn: Option<ReloadableDatabase>,
+}
+
+impl GeoIp {
+    /// Returns `None` if neither GEOIP_CITY_DB nor GEOIP_ASN_DB is set.
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        if settings.geoip_city_db.is_none() && settings.geoip_asn_db.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            city: settings
+                .geoip_city_db
+                .as_deref()
+                .map(ReloadableDatabase::open)
+                .transpose()?,
+            asn: settings
+                .geoip_asn_db
+                .as_deref()
+                .map(ReloadableDatabase::open)
+                .transpose()?,
+        }))
+    }
+
+    pub fn lookup(&mut self, ip: IpAddr) -> Location {
+        let now = Instant::now();
+        let mut location = Location::default();
+
+        // Country databases use a subset of the city database's format, so
+        // both can be read as a city database.
+        if let Some(db) = &mut self.city {
+            db.reload_if_changed(now);
+            if let Some(city) = db.lookup::<geoip2::City>(ip) {
+                location.country_code = city
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(str::to_owned);
+                location.region = city
+                    .subdivisions
+                    .and_then(|subdivisions| subdivisions.into_iter().next())
+                    .and_then(|subdivision| subdivision.names)
+                    .and_then(|names| names.get(NAME_LANGUAGE).map(|name| name.to_string()));
+                location.city = city
+                    .city
+                    .and_then(|city| city.names)
+                    .and_then(|names| names.get(NAME_LANGUAGE).map(|name| name.to_string()));
+            }
+        }
+
+        if let Some(db) = &mut self.asn {
+            db.reload_if_changed(now);
+            if let Some(asn) = db.lookup::<geoip2::Asn>(ip) {
+                location.asn = asn.autonomous_system_number.map(i64::from);
+                location.as_org = asn.autonomous_system_organization.map(str::to_owned);
+            }
+        }
+
+        location
+    }
+}
+
+/// A database that gets loaded into memory, and loaded again once the file
+/// on disk changes.
+struct ReloadableDatabase {
+    path: PathBuf,
+    reader: Reader<Vec<u8>>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+    /// The result of a reload that's running in the background.
+    reloaded: Option<oneshot::Receiver<Result<Self>>>,
+}
+
+impl ReloadableDatabase {
+    fn open(path: &Path) -> Result<Self> {
+        let modified = modified(path);
+        let reader =
+            Reader::open_readfile(path).with_context(|| format!("reading {}", path.display()))?;
+        info!(
+            "Loaded {} from {}",
+            reader.metadata.database_type,
+            path.display()
+        );
+
+        Ok(Self {
+            path: path.to_owned(),
+            reader,
+            modified,
+            checked_at: Instant::now(),
+            reloaded: None,
+        })
+    }
+
+    /// Reading a database file blocks for a while, so a changed file is
+    /// loaded on the blocking thread pool, and the previous version is used
+    /// until that's done. If loading the changed file fails, like if it's
+    /// only partially written, the previous version stays in use, and
+    /// loading is tried again at the next check.
+    fn reload_if_changed(&mut self, now: Instant) {
+        if let Some(reloaded) = &mut self.reloaded {
+            match reloaded.try_recv() {
+                Ok(Ok(reloaded)) => *self = reloaded,
+                Ok(Err(err)) => warn!("Could not reload {}: {:#}", self.path.display(), err),
+                Err(oneshot::error::TryRecvError::Empty) => return,
+                Err(oneshot::error::TryRecvError::Closed) => {}
+            }
+            self.reloaded = None;
+        }
+
+        if now.saturating_duration_since(self.checked_at) < RELOAD_CHECK_INTERVAL {
+            return;
+        }
+        self.checked_at = now;
+
+        let modified = modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            // The receiver is only gone if the bridge is shutting down.
+            let _ = sender.send(Self::open(&path));
+        });
+        self.reloaded = Some(receiver);
+    }
+
+    fn lookup<'a, T: serde::Deserialize<'a>>(&'a self, ip: IpAddr) -> Option<T> {
+        match self.reader.lookup(ip) {
+            Ok(record) => Some(record),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+            Err(err) => {
+                debug!(
+                    "Looking up {} in {} failed: {}",
+                    ip,
+                    self.path.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds a tiny IPv4 database in the MaxMind DB format, where everything
+    /// in 128.0.0.0/1 has the same record, and everything else is missing.
+    fn build_database(city: &str, asn: u32) -> Vec<u8> {
+        // Sizes from 29 on need an extra byte.
+        fn string(value: &str) -> Vec<u8> {
+            let size = match value.len() {
+                len @ ..29 => vec![0x40 | len as u8],
+                len => vec![0x40 | 29, (len - 29) as u8],
+            };
+            [size, value.as_bytes().to_vec()].concat()
+        }
+        fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+            let mut bytes = vec![0xE0 | entries.len() as u8];
+            for (key, value) in entries {
+                bytes.extend(string(key));
+                bytes.extend(value);
+            }
+            bytes
+        }
+        fn uint16(value: u16) -> Vec<u8> {
+            [vec![0xA2], value.to_be_bytes().to_vec()].concat()
+        }
+        fn uint32(value: u32) -> Vec<u8> {
+            [vec![0xC4], value.to_be_bytes().to_vec()].concat()
+        }
+        fn uint64(value: u64) -> Vec<u8> {
+            [vec![0x08, 0x02], value.to_be_bytes().to_vec()].concat()
+        }
+        fn array(values: &[Vec<u8>]) -> Vec<u8> {
+            [vec![values.len() as u8, 0x04], values.concat()].concat()
+        }
+
+        let names = |name: &str| map(&[("en", string(name))]);
+        let record = map(&[
+            ("autonomous_system_number", uint32(asn)),
+            ("autonomous_system_organization", string("Example Networks")),
+            ("city", map(&[("names", names(city))])),
+            ("country", map(&[("iso_code", string("DE"))])),
+            ("subdivisions", array(&[map(&[("names", names("Berlin"))])])),
+        ]);
+        let metadata = map(&[
+            ("binary_format_major_version", uint16(2)),
+            ("binary_format_minor_version", uint16(0)),
+            ("build_epoch", uint64(0)),
+            ("database_type", string("Test")),
+            ("description", map(&[])),
+            ("ip_version", uint16(4)),
+            ("languages", array(&[string("en")])),
+            ("node_count", uint32(1)),
+            ("record_size", uint16(24)),
+        ]);
+
+        // One node, whose left record means "not found" and whose right
+        // record points to the start of the data section.
+        let tree = [0, 0, 1, 0, 0, 17];
+        [
+            tree.to_vec(),
+            vec![0; 16],
+            record,
+            b"\xAB\xCD\xEFMaxMind.com".to_vec(),
+            metadata,
+        ]
+        .concat()
+    }
+
+    fn write_database(city: &str, asn: u32) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ngxslpg-geoip-{}.mmdb", uuid::Uuid::new_v4()));
+        std::fs::write(&path, build_database(city, asn)).unwrap();
+        path
+    }
+
+    #[test]
+    fn looks_up_addresses() {
+        let path = write_database("Berlin", 64496);
+        let mut geoip = GeoIp {
+            city: Some(ReloadableDatabase::open(&path).unwrap()),
+            asn: Some(ReloadableDatabase::open(&path).unwrap()),
+        };
+
+        assert_eq!(
+            Location {
+                country_code: Some("DE".to_owned()),
+                region: Some("Berlin".to_owned()),
+                city: Some("Berlin".to_owned()),
+                asn: Some(64496),
+                as_org: Some("Example Networks".to_owned()),
+            },
+            geoip.lookup("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            Location::default(),
+            geoip.lookup("10.0.0.1".parse().unwrap())
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Starts a reload after the check interval, and waits until it's done.
+    async fn reload(db: &mut ReloadableDatabase) {
+        db.reload_if_changed(Instant::now() + RELOAD_CHECK_INTERVAL);
+        assert!(db.reloaded.is_some());
+        while db.reloaded.is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            db.reload_if_changed(Instant::now());
+        }
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_files() {
+        let path = write_database("Berlin", 64496);
+        let mut db = ReloadableDatabase::open(&path).unwrap();
+
+        std::fs::write(&path, build_database("Hamburg", 64497)).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+
+        // Changes are only picked up after the check interval.
+        db.reload_if_changed(Instant::now());
+        assert!(db.reloaded.is_none());
+        let asn: geoip2::Asn = db.lookup("192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(Some(64496), asn.autonomous_system_number);
+
+        reload(&mut db).await;
+        let asn: geoip2::Asn = db.lookup("192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(Some(64497), asn.autonomous_system_number);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_previous_database_if_reload_fails() {
+        let path = write_database("Berlin", 64496);
+        let mut db = ReloadableDatabase::open(&path).unwrap();
+
+        std::fs::write(&path, "meow").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+
+        reload(&mut db).await;
+        let asn: geoip2::Asn = db.lookup("192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(Some(64496), asn.autonomous_system_number);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+
+/// Splits an `X-Forwarded-For` header into its addresses. Like nginx, this
+/// accepts addresses with a port, like `192.0.2.1:1234` or `[2001:db8::1]:80`.
+pub fn parse_forwarded_for(forwarded_for: &str) -> Vec<Option<IpAddr>> {
+    forwarded_for
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            addr.parse::<IpAddr>()
+                .or_else(|_| addr.parse::<SocketAddr>().map(|addr| addr.ip()))
+                .ok()
+                .map(|ip| ip.to_canonical())
+        })
+        .collect()
+}
+
+/// Finds the real client address the same way nginx' realip module does with
+/// `real_ip_header X-Forwarded-For`.
+pub struct RealIp {
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Same as `real_ip_recursive on`.
+    pub recursive: bool,
+}
+
+impl RealIp {
+    /// If the connecting address is a trusted proxy, the header gets walked
+    /// from the right. Without `recursive`, the last address in the header is
+    /// the client. With it, the first address that isn't a trusted proxy is
+    /// the client. An invalid address in the header stops the search, and the
+    /// last address found before that is the client.
+    pub fn real_addr(
+        &self,
+        addr: Option<IpAddr>,
+        forwarded_for: &[Option<IpAddr>],
+    ) -> Option<IpAddr> {
+        let mut real_addr = addr?;
+        if !self.is_trusted(real_addr) {
+            return Some(real_addr);
+        }
+
+        for addr in forwarded_for.iter().rev() {
+            let Some(addr) = *addr else {
+                break;
+            };
+
+            real_addr = addr;
+            if !self.recursive || !self.is_trusted(addr) {
+                break;
+            }
+        }
+
+        Some(real_addr)
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn real_ip(trusted_proxies: &[&str], recursive: bool) -> RealIp {
+        RealIp {
+            trusted_proxies: trusted_proxies.iter().map(|s| s.parse().unwrap()).collect(),
+            recursive,
+        }
+    }
+
+    fn real_addr(real_ip: &RealIp, addr: &str, forwarded_for: &str) -> IpAddr {
+        real_ip
+            .real_addr(
+                Some(addr.parse().unwrap()),
+                &parse_forwarded_for(forwarded_for),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_forwarded_for() {
+        assert_eq!(
+            vec![
+                Some("192.0.2.1".parse().unwrap()),
+                None,
+                Some("2001:db8::1".parse().unwrap()),
+                Some("198.51.100.1".parse().unwrap()),
+                Some("10.0.0.1".parse().unwrap()),
+            ],
+            parse_forwarded_for(
+                "192.0.2.1, unknown,[2001:db8::1]:443, 198.51.100.1:1234, ::ffff:10.0.0.1"
+            )
+        );
+        assert!(parse_forwarded_for("").is_empty());
+    }
+
+    #[test]
+    fn ignores_header_from_untrusted_addresses() {
+        let real_ip = real_ip(&["10.0.0.0/8"], true);
+        assert_eq!(
+            "192.0.2.1".parse::<IpAddr>().unwrap(),
+            real_addr(&real_ip, "192.0.2.1", "198.51.100.1")
+        );
+        assert!(real_ip.real_addr(None, &[]).is_none());
+    }
+
+    #[test]
+    fn uses_last_address_without_recursion() {
+        let real_ip = real_ip(&["10.0.0.0/8"], false);
+        assert_eq!(
+            "10.0.0.2".parse::<IpAddr>().unwrap(),
+            real_addr(&real_ip, "10.0.0.1", "198.51.100.1, 10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn skips_trusted_proxies_with_recursion() {
+        let real_ip = real_ip(&["10.0.0.0/8"], true);
+        assert_eq!(
+            "198.51.100.1".parse::<IpAddr>().unwrap(),
+            real_addr(&real_ip, "10.0.0.1", "203.0.113.1, 198.51.100.1, 10.0.0.2")
+        );
+        assert_eq!(
+            "10.0.0.3".parse::<IpAddr>().unwrap(),
+            real_addr(&real_ip, "10.0.0.1", "10.0.0.3, 10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn stops_at_invalid_addresses() {
+        let real_ip = real_ip(&["10.0.0.0/8"], true);
+        assert_eq!(
+            "10.0.0.2".parse::<IpAddr>().unwrap(),
+            real_addr(&real_ip, "10.0.0.1", "198.51.100.1, unknown, 10.0.0.2")
+        );
+        assert_eq!(
+            "10.0.0.1".parse::<IpAddr>().unwrap(),
+            real_addr(&real_ip, "10.0.0.1", "meow")
+        );
+    }
+}
@@ -0,0 +1,114 @@
+/// What the values of redacted query parameters get replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Splits a `$request_uri` into the path and the query string, without the
+/// `?`. Empty query strings are treated like missing ones.
+pub fn split_uri(uri: &str) -> (&str, Option<&str>) {
+    match uri.split_once('?') {
+        Some((path, query)) => (path, (!query.is_empty()).then_some(query)),
+        None => (uri, None),
+    }
+}
+
+/// Replaces the values of all query parameters whose names are in `params`
+/// with [REDACTED]. Names are compared case-insensitively and after decoding,
+/// so `Token`, `token`, and `t%6Fken` are all the same parameter. `params`
+/// has to be lowercase already.
+pub fn redact_query(query: &str, params: &[String]) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if params.contains(&decode(name).to_lowercase()) => {
+                format!("{}={}", name, REDACTED)
+            }
+            _ => pair.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Redacts the query string of a URI, which can be a `$request_uri` or a
+/// full URL, like a referrer. The fragment, if there is one, is kept.
+pub fn redact_uri(uri: &str, params: &[String]) -> String {
+    let (uri, fragment) = match uri.split_once('#') {
+        Some((uri, fragment)) => (uri, Some(fragment)),
+        None => (uri, None),
+    };
+
+    let mut redacted = match split_uri(uri) {
+        (path, Some(query)) => format!("{}?{}", path, redact_query(query, params)),
+        _ => uri.to_owned(),
+    };
+    if let Some(fragment) = fragment {
+        redacted.push('#');
+        redacted.push_str(fragment);
+    }
+
+    redacted
+}
+
+/// Decodes `+` and percent-encoded bytes in a query parameter name.
+/// Anything that isn't valid is kept as it is.
+fn decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut remaining = value.as_bytes();
+    while let Some((&byte, rest)) = remaining.split_first() {
+        let decoded = rest
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, decoded) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                remaining = &rest[2..];
+            }
+            (b'+', _) => {
+                bytes.push(b' ');
+                remaining = rest;
+            }
+            _ => {
+                bytes.push(byte);
+                remaining = rest;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn splits_uri() {
+        assert_eq!(("/search", Some("q=meow")), split_uri("/search?q=meow"));
+        assert_eq!(("/search", Some("q=a?b")), split_uri("/search?q=a?b"));
+        assert_eq!(("/search", None), split_uri("/search?"));
+        assert_eq!(("/", None), split_uri("/"));
+    }
+
+    #[test]
+    fn redacts_query_params() {
+        let params = vec!["token".to_owned(), "email".to_owned()];
+        assert_eq!(
+            "q=meow&Token=[REDACTED]&e%6Dail=[REDACTED]&token&tokens=1",
+            redact_query(
+                "q=meow&Token=abc&e%6Dail=alice%40example.com&token&tokens=1",
+                &params
+            )
+        );
+        assert_eq!("q=meow", redact_query("q=meow", &[]));
+    }
+
+    #[test]
+    fn redacts_uris() {
+        let params = vec!["token".to_owned()];
+        assert_eq!(
+            "https://example.com/a?token=[REDACTED]&q=1#top",
+            redact_uri("https://example.com/a?token=abc&q=1#top", &params)
+        );
+        assert_eq!("/a#token=abc", redact_uri("/a#token=abc", &params));
+        assert_eq!("/a", redact_uri("/a", &params));
+    }
+}
@@ -0,0 +1,313 @@
+use std::{num::NonZeroUsize, path::Path};
+
+use anyhow::{Context, Result};
+use lru::LruCache;
+use regex::{Captures, Regex, RegexBuilder};
+use serde::Deserialize;
+use tracing::warn;
+
+/// The regexes that are used if UA_REGEXES_FILE isn't set.
+const BUNDLED_REGEXES: &str = include_str!("user_agent_regexes.yaml");
+
+/// uap-core puts all crawlers into this device family.
+const BOT_DEVICE: &str = "Spider";
+
+/// What a user agent string was classified as. Everything is `None` if the
+/// entry didn't have a user agent, or if user agent parsing is disabled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserAgent {
+    pub browser: Option<String>,
+    pub browser_version: Option<String>,
+    pub os: Option<String>,
+    pub device: Option<String>,
+    pub is_bot: Option<bool>,
+}
+
+/// One entry in a uap-core `regexes.yaml`. The three sections use different
+/// names for the same things, so they're all mapped to the names of the
+/// `user_agent_parsers` section.
+#[derive(Deserialize)]
+struct Pattern {
+    regex: String,
+
+    regex_flag: Option<String>,
+
+    #[serde(alias = "os_replacement", alias = "device_replacement")]
+    family_replacement: Option<String>,
+
+    #[serde(alias = "os_v1_replacement")]
+    v1_replacement: Option<String>,
+
+    #[serde(alias = "os_v2_replacement")]
+    v2_replacement: Option<String>,
+
+    #[serde(alias = "os_v3_replacement")]
+    v3_replacement: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RegexesFile {
+    user_agent_parsers: Vec<Pattern>,
+    os_parsers: Vec<Pattern>,
+    device_parsers: Vec<Pattern>,
+}
+
+struct Matcher {
+    regex: Regex,
+
+    /// The replacements for the family and the three version parts.
+    replacements: [Option<String>; 4],
+}
+
+impl Matcher {
+    /// Returns the family and version parts if the regex matches. Without a
+    /// replacement, they're the regex's groups, in order. Replacements can
+    /// refer to groups as `$1` to `$9`.
+    fn captures(&self, ua: &str) -> Option<[Option<String>; 4]> {
+        let captures = self.regex.captures(ua)?;
+        Some(std::array::from_fn(|i| {
+            let value = match &self.replacements[i] {
+                Some(replacement) => expand(replacement, &captures),
+                None => captures
+                    .get(i + 1)
+                    .map_or(String::new(), |m| m.as_str().to_owned()),
+            };
+            let value = value.trim();
+            (!value.is_empty()).then(|| value.to_owned())
+        }))
+    }
+}
+
+/// Classifies user agent strings with uap-core style regexes. User agents
+/// repeat a lot, so results are cached.
+pub struct UserAgentParser {
+    user_agents: Vec<Matcher>,
+    oses: Vec<Matcher>,
+    devices: Vec<Matcher>,
+    cache: LruCache<String, UserAgent>,
+}
+
+impl UserAgentParser {
+    /// Loads the regexes from `path`, or the bundled ones if that's `None`.
+    pub fn load(path: Option<&Path>, cache_size: usize) -> Result<Self> {
+        match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                Self::new(&content, cache_size)
+                    .with_context(|| format!("parsing {}", path.display()))
+            }
+            None => Self::new(BUNDLED_REGEXES, cache_size),
+        }
+    }
+
+    pub fn new(regexes: &str, cache_size: usize) -> Result<Self> {
+        let file: RegexesFile = serde_yaml::from_str(regexes)?;
+        Ok(Self {
+            user_agents: compile(file.user_agent_parsers),
+            oses: compile(file.os_parsers),
+            devices: compile(file.device_parsers),
+            cache: LruCache::new(NonZeroUsize::new(cache_size).unwrap_or(NonZeroUsize::MIN)),
+        })
+    }
+
+    pub fn parse(&mut self, ua: &str) -> UserAgent {
+        if let Some(user_agent) = self.cache.get(ua) {
+            return user_agent.clone();
+        }
+
+        let [browser, v1, v2, v3] = first_match(&self.user_agents, ua);
+        let [os, ..] = first_match(&self.oses, ua);
+        let [device, ..] = first_match(&self.devices, ua);
+
+        let user_agent = UserAgent {
+            browser,
+            browser_version: v1.map(|v1| {
+                [Some(v1), v2, v3]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join(".")
+            }),
+            os,
+            is_bot: Some(device.as_deref() == Some(BOT_DEVICE)),
+            device,
+        };
+
+        self.cache.put(ua.to_owned(), user_agent.clone());
+        user_agent
+    }
+}
+
+/// Some of uap-core's patterns use regex features the regex crate doesn't
+/// support. Those get skipped, since the remaining patterns are still useful.
+fn compile(patterns: Vec<Pattern>) -> Vec<Matcher> {
+    let mut skipped = 0;
+    let matchers: Vec<Matcher> = patterns
+        .into_iter()
+        .filter_map(|pattern| {
+            let regex = RegexBuilder::new(&pattern.regex)
+                .case_insensitive(pattern.regex_flag.as_deref() == Some("i"))
+                .build();
+            match regex {
+                Ok(regex) => Some(Matcher {
+                    regex,
+                    replacements: [
+                        pattern.family_replacement,
+                        pattern.v1_replacement,
+                        pattern.v2_replacement,
+                        pattern.v3_replacement,
+                    ],
+                }),
+                Err(err) => {
+                    skipped += 1;
+                    warn!("Skipping user agent pattern `{}`: {}", pattern.regex, err);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    if skipped > 0 {
+        warn!(
+            "Skipped {} user agent patterns that could not be compiled",
+            skipped
+        );
+    }
+
+    matchers
+}
+
+fn first_match(matchers: &[Matcher], ua: &str) -> [Option<String>; 4] {
+    matchers
+        .iter()
+        .find_map(|matcher| matcher.captures(ua))
+        .unwrap_or_default()
+}
+
+/// Replaces `$1` to `$9` with the matching groups, or nothing if a group
+/// didn't match.
+fn expand(replacement: &str, captures: &Captures) -> String {
+    let mut expanded = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().and_then(|next| next.to_digit(10))) {
+            ('$', Some(group)) if group > 0 => {
+                chars.next();
+                if let Some(m) = captures.get(group as usize) {
+                    expanded.push_str(m.as_str());
+                }
+            }
+            _ => expanded.push(c),
+        }
+    }
+
+    expanded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(ua: &str) -> UserAgent {
+        UserAgentParser::load(None, 10).unwrap().parse(ua)
+    }
+
+    #[test]
+    fn parses_desktop_browsers() {
+        assert_eq!(
+            UserAgent {
+                browser: Some("Firefox".to_owned()),
+                browser_version: Some("105.0".to_owned()),
+                os: Some("Mac OS X".to_owned()),
+                device: Some("Mac".to_owned()),
+                is_bot: Some(false),
+            },
+            parse(
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:105.0) Gecko/20100101 Firefox/105.0"
+            )
+        );
+        assert_eq!(
+            UserAgent {
+                browser: Some("Edge".to_owned()),
+                browser_version: Some("129.0.0".to_owned()),
+                os: Some("Windows".to_owned()),
+                device: None,
+                is_bot: Some(false),
+            },
+            parse(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/129.0.0.0 Safari/537.36 Edg/129.0.0.0"
+            )
+        );
+    }
+
+    #[test]
+    fn parses_mobile_browsers() {
+        let ua = parse(
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.5 Mobile/15E148 Safari/604.1",
+        );
+        assert_eq!(Some("Mobile Safari".to_owned()), ua.browser);
+        assert_eq!(Some("17.5".to_owned()), ua.browser_version);
+        assert_eq!(Some("iOS".to_owned()), ua.os);
+        assert_eq!(Some("iPhone".to_owned()), ua.device);
+    }
+
+    #[test]
+    fn detects_bots() {
+        let ua = parse("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)");
+        assert_eq!(Some("Googlebot".to_owned()), ua.browser);
+        assert_eq!(Some(true), ua.is_bot);
+        assert_eq!(Some(true), parse("curl/8.0.1").is_bot);
+    }
+
+    #[test]
+    fn is_empty_for_unknown_user_agents() {
+        assert_eq!(
+            UserAgent {
+                is_bot: Some(false),
+                ..Default::default()
+            },
+            parse("meow")
+        );
+    }
+
+    #[test]
+    fn supports_replacements_and_flags() {
+        let mut parser = UserAgentParser::new(
+            r#"
+user_agent_parsers:
+  - regex: 'Meow/(\d+)'
+    family_replacement: 'Cat $1'
+    v1_replacement: '$1'
+os_parsers: []
+device_parsers:
+  - regex: 'meow'
+    regex_flag: 'i'
+    device_replacement: 'Spider'
+"#,
+            10,
+        )
+        .unwrap();
+
+        let ua = parser.parse("Meow/3");
+        assert_eq!(Some("Cat 3".to_owned()), ua.browser);
+        assert_eq!(Some("3".to_owned()), ua.browser_version);
+        assert_eq!(Some(true), ua.is_bot);
+    }
+
+    #[test]
+    fn skips_unsupported_patterns() {
+        let parser = UserAgentParser::new(
+            r#"
+user_agent_parsers:
+  - regex: '(?<!Meow)Cat'
+  - regex: '(Cat)'
+os_parsers: []
+device_parsers: []
+"#,
+            10,
+        )
+        .unwrap();
+        assert_eq!(1, parser.user_agents.len());
+    }
+}
@@ -0,0 +1,86 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use sqlx::{Postgres, postgres::PgArguments, query::Query};
+use uuid::Uuid;
+
+use crate::{
+    column_vecs::{column_vecs_impl, column_vecs_push_body},
+    parsers::ErrorLogEntry,
+};
+
+/// The `error_log` counterpart to [crate::AccessLogColumnVecs].
+pub struct ErrorLogColumnVecs {
+    pub id: Vec<Uuid>,
+    pub hostname: Vec<Option<String>>,
+    pub ts: Vec<DateTime<Utc>>,
+    pub level: Vec<String>,
+    pub pid: Vec<Option<i32>>,
+    pub tid: Vec<Option<i64>>,
+    pub connection_id: Vec<Option<i64>>,
+    pub message: Vec<String>,
+    pub client: Vec<Option<String>>,
+    pub server: Vec<Option<String>>,
+    pub request: Vec<Option<String>>,
+    pub subrequest: Vec<Option<String>>,
+    pub upstream: Vec<Option<String>>,
+    pub host: Vec<Option<String>>,
+    pub referrer: Vec<Option<String>>,
+    pub syslog_facility: Vec<Option<String>>,
+    pub syslog_severity: Vec<Option<String>>,
+    pub syslog_tag: Vec<Option<String>>,
+    pub syslog_ts: Vec<Option<DateTime<Utc>>>,
+    pub source_addr: Vec<Option<IpAddr>>,
+}
+
+column_vecs_impl! {
+    ErrorLogColumnVecs {
+        id => id::uuid,
+        hostname => hostname::text,
+        ts => event_ts::timestamptz,
+        level => level::text,
+        pid => pid::int4,
+        tid => tid::int8,
+        connection_id => connection_id::int8,
+        message => message::text,
+        client => client::text,
+        server => server::text,
+        request => request::text,
+        subrequest => subrequest::text,
+        upstream => upstream::text,
+        host => host::text,
+        referrer => referrer::text,
+        syslog_facility => syslog_facility::text,
+        syslog_severity => syslog_severity::text,
+        syslog_tag => syslog_tag::text,
+        syslog_ts => syslog_ts::timestamptz,
+        source_addr => source_addr::inet,
+    }
+}
+
+impl ErrorLogColumnVecs {
+    pub fn push(&mut self, entry: ErrorLogEntry) {
+        column_vecs_push_body!(self, entry, {
+            id: Uuid::new_v4(),
+            hostname: entry.hostname,
+            ts: entry.ts,
+            level: entry.level,
+            pid: entry.pid,
+            tid: entry.tid,
+            connection_id: entry.connection_id,
+            message: entry.message,
+            client: entry.client,
+            server: entry.server,
+            request: entry.request,
+            subrequest: entry.subrequest,
+            upstream: entry.upstream,
+            host: entry.host,
+            referrer: entry.referrer,
+            syslog_facility: entry.syslog.facility,
+            syslog_severity: entry.syslog.severity,
+            syslog_tag: entry.syslog.tag,
+            syslog_ts: entry.syslog.ts,
+            source_addr: entry.syslog.source_addr,
+        });
+    }
+}
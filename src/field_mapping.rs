@@ -0,0 +1,417 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sqlx::PgPool;
+use tracing::{debug, info};
+
+use crate::{AccessLogColumnVecs, column_vecs::Column, settings::Settings};
+
+/// PostgreSQL truncates longer identifiers, which would make the column name
+/// in the database differ from the one in the mapping.
+const MAX_COLUMN_NAME_LENGTH: usize = 63;
+
+/// The SQL types a mapped field can be stored as.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Text,
+    Integer,
+    Bigint,
+    Float,
+    Boolean,
+}
+
+impl FieldType {
+    /// The type name used in casts and `ALTER TABLE`.
+    pub fn sql_type(self) -> &'static str {
+        match self {
+            FieldType::Text => "text",
+            FieldType::Integer => "int4",
+            FieldType::Bigint => "int8",
+            FieldType::Float => "float8",
+            FieldType::Boolean => "bool",
+        }
+    }
+
+    /// The type name as it shows up in `information_schema.columns`.
+    fn data_type(self) -> &'static str {
+        match self {
+            FieldType::Text => "text",
+            FieldType::Integer => "integer",
+            FieldType::Bigint => "bigint",
+            FieldType::Float => "double precision",
+            FieldType::Boolean => "boolean",
+        }
+    }
+
+    /// Converts a JSON value into this type. nginx sends all variables as
+    /// strings, and empty strings for unset variables, so strings get parsed,
+    /// and empty ones are treated like missing values.
+    fn convert(self, value: Option<&Value>) -> Result<FieldValue> {
+        let value = match value {
+            None | Some(Value::Null) => None,
+            Some(Value::String(s)) if s.trim().is_empty() => None,
+            Some(value) => Some(value),
+        };
+
+        Ok(match self {
+            FieldType::Text => FieldValue::Text(value.map(|value| match value {
+                Value::String(s) => s.trim().to_owned(),
+                other => other.to_string(),
+            })),
+            FieldType::Integer => FieldValue::Integer(value.map(parse_number).transpose()?),
+            FieldType::Bigint => FieldValue::Bigint(value.map(parse_number).transpose()?),
+            FieldType::Float => FieldValue::Float(value.map(parse_number).transpose()?),
+            FieldType::Boolean => FieldValue::Boolean(value.map(parse_boolean).transpose()?),
+        })
+    }
+
+    /// The value that gets stored for missing and invalid values.
+    fn null(self) -> FieldValue {
+        match self {
+            FieldType::Text => FieldValue::Text(None),
+            FieldType::Integer => FieldValue::Integer(None),
+            FieldType::Bigint => FieldValue::Bigint(None),
+            FieldType::Float => FieldValue::Float(None),
+            FieldType::Boolean => FieldValue::Boolean(None),
+        }
+    }
+}
+
+/// One value extracted from a log line, already converted into the type of
+/// its column.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Text(Option<String>),
+    Integer(Option<i32>),
+    Bigint(Option<i64>),
+    Float(Option<f64>),
+    Boolean(Option<bool>),
+}
+
+/// A JSON field from a custom `log_format` that gets stored in its own
+/// column in `access_log`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappedField {
+    /// The field's path in the JSON document, with nested keys separated by
+    /// dots, like `req.id`.
+    pub path: String,
+
+    pub column: String,
+
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+}
+
+/// All additional fields configured in FIELD_MAPPING_FILE. This is empty if
+/// there's no mapping file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldMapping {
+    #[serde(default, rename = "field")]
+    pub fields: Vec<MappedField>,
+}
+
+impl FieldMapping {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        match &settings.field_mapping_file {
+            Some(path) => Self::load(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn parse(toml: &str) -> Result<Self> {
+        let mapping: Self = toml::from_str(toml)?;
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    /// The mapped columns, in the order of [Self::fields].
+    pub fn columns(&self) -> impl Iterator<Item = Column<'_>> {
+        self.fields.iter().map(|field| Column {
+            name: &field.column,
+            sql_type: field.field_type.sql_type(),
+            cast: None,
+        })
+    }
+
+    /// Adds all mapped columns that don't exist yet to `access_log`. Columns
+    /// that do exist already have to have the right type. Columns are never
+    /// removed, so removing a field from the mapping doesn't lose any data.
+    pub async fn migrate(&self, db_pool: &PgPool) -> Result<()> {
+        for field in &self.fields {
+            // Column names are validated to be plain identifiers, so they're
+            // safe to put into the query. They're quoted anyway, since plain
+            // identifiers can still be reserved words, like `user`.
+            sqlx::query(&format!(
+                "ALTER TABLE access_log ADD COLUMN IF NOT EXISTS \"{}\" {}",
+                field.column,
+                field.field_type.sql_type()
+            ))
+            .execute(db_pool)
+            .await
+            .with_context(|| format!("adding column `{}` to access_log", field.column))?;
+
+            let (data_type,): (String,) = sqlx::query_as(
+                "SELECT data_type::text FROM information_schema.columns
+                 WHERE table_schema = current_schema() AND table_name = 'access_log' AND column_name = $1",
+            )
+            .bind(&field.column)
+            .fetch_one(db_pool)
+            .await?;
+            if data_type != field.field_type.data_type() {
+                bail!(
+                    "column `{}` already exists as {}, but is mapped as {}",
+                    field.column,
+                    data_type,
+                    field.field_type.data_type()
+                );
+            }
+        }
+
+        if !self.fields.is_empty() {
+            info!("Using {} mapped fields", self.fields.len());
+        }
+
+        Ok(())
+    }
+
+    /// Extracts the values of all mapped fields from an access_log entry.
+    /// Mapped fields often contain values the client controls, like headers,
+    /// so values that can't be converted are stored as `NULL` instead of
+    /// rejecting the whole entry. The paths of these fields are returned as
+    /// well.
+    pub fn extract(&self, json: &Value) -> (Vec<FieldValue>, Vec<&str>) {
+        let mut invalid_fields = vec![];
+        let values = self
+            .fields
+            .iter()
+            .map(|field| {
+                let value = field
+                    .path
+                    .split('.')
+                    .try_fold(json, |value, key| value.get(key));
+                field.field_type.convert(value).unwrap_or_else(|err| {
+                    debug!("Invalid value for mapped field `{}`: {}", field.path, err);
+                    invalid_fields.push(field.path.as_str());
+                    field.field_type.null()
+                })
+            })
+            .collect();
+
+        (values, invalid_fields)
+    }
+
+    /// Removes all mapped fields from the unknown fields of an entry, since
+    /// they're stored in their own columns already. Objects that are empty
+    /// after that get removed as well.
+    pub fn remove_mapped(&self, extra: &mut Map<String, Value>) {
+        for field in &self.fields {
+            let path: Vec<&str> = field.path.split('.').collect();
+            remove_path(extra, &path);
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut columns: HashSet<&str> = AccessLogColumnVecs::COLUMNS
+            .iter()
+            .map(|column| column.name)
+            .collect();
+
+        for field in &self.fields {
+            if field.path.split('.').any(str::is_empty) {
+                bail!("`{}` is not a valid path", field.path);
+            }
+
+            let column = field.column.as_str();
+            let is_identifier = column
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+                && column
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if !is_identifier || column.len() > MAX_COLUMN_NAME_LENGTH {
+                bail!(
+                    "`{}` is not a valid column name, only lowercase letters, digits, and underscores are allowed",
+                    column
+                );
+            }
+
+            if !columns.insert(column) {
+                bail!("column `{}` is used more than once", column);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn remove_path(object: &mut Map<String, Value>, path: &[&str]) {
+    match path {
+        [] => {}
+        [key] => {
+            object.remove(*key);
+        }
+        [key, rest @ ..] => {
+            if let Some(Value::Object(nested)) = object.get_mut(*key) {
+                remove_path(nested, rest);
+                if nested.is_empty() {
+                    object.remove(*key);
+                }
+            }
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &Value) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(match value {
+        Value::String(s) => s.trim().parse()?,
+        Value::Number(n) => n.to_string().parse()?,
+        other => bail!("expected a number, got {}", other),
+    })
+}
+
+fn parse_boolean(value: &Value) -> Result<bool> {
+    Ok(match value {
+        Value::Bool(b) => *b,
+        Value::String(s) => match s.trim() {
+            "1" | "on" | "true" | "yes" => true,
+            "0" | "off" | "false" | "no" => false,
+            other => bail!("expected a boolean, got `{}`", other),
+        },
+        Value::Number(n) if n.as_u64() == Some(1) => true,
+        Value::Number(n) if n.as_u64() == Some(0) => false,
+        other => bail!("expected a boolean, got {}", other),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAPPING: &str = r#"
+        [[field]]
+        path = "req.id"
+        column = "req_id"
+        type = "text"
+
+        [[field]]
+        path = "ssl.reused"
+        column = "ssl_session_reused"
+        type = "boolean"
+
+        [[field]]
+        path = "tenant"
+        column = "tenant_id"
+        type = "bigint"
+    "#;
+
+    #[test]
+    fn parses_mapping() {
+        let mapping = FieldMapping::parse(MAPPING).unwrap();
+        assert_eq!(
+            vec![
+                ("req_id", "text"),
+                ("ssl_session_reused", "bool"),
+                ("tenant_id", "int8")
+            ],
+            mapping
+                .columns()
+                .map(|column| (column.name, column.sql_type))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn is_err_for_invalid_columns() {
+        let mapping = |column: &str| {
+            FieldMapping::parse(&format!(
+                "[[field]]\npath = \"a\"\ncolumn = \"{}\"\ntype = \"text\"",
+                column
+            ))
+        };
+
+        assert!(mapping("tenant_id").is_ok());
+        assert!(mapping("hostname").is_err());
+        assert!(mapping("Tenant").is_err());
+        assert!(mapping("1tenant").is_err());
+        assert!(mapping("tenant; DROP TABLE access_log").is_err());
+        assert!(mapping(&"a".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn extracts_and_converts_values() {
+        let mapping = FieldMapping::parse(MAPPING).unwrap();
+        let json = serde_json::json!({
+            "req": {"id": " abc "},
+            "ssl": {"reused": "maybe"},
+            "tenant": "1; DROP TABLE access_log",
+        });
+        assert_eq!(
+            (
+                vec![
+                    FieldValue::Text(Some("abc".to_owned())),
+                    FieldValue::Boolean(None),
+                    FieldValue::Bigint(None),
+                ],
+                vec!["ssl.reused", "tenant"]
+            ),
+            mapping.extract(&json)
+        );
+
+        let json = serde_json::json!({
+            "req": {"id": "abc"},
+            "ssl": {"reused": ""},
+            "tenant": 42,
+        });
+        assert_eq!(
+            vec![
+                FieldValue::Text(Some("abc".to_owned())),
+                FieldValue::Boolean(None),
+                FieldValue::Bigint(Some(42)),
+            ],
+            mapping.extract(&json).0
+        );
+    }
+
+    #[test]
+    fn removes_mapped_fields_from_extra() {
+        let mapping = FieldMapping::parse(MAPPING).unwrap();
+        let serde_json::Value::Object(mut extra) = serde_json::json!({
+            "req": {"id": "abc"},
+            "ssl": {"reused": "1", "protocol": "TLSv1.3"},
+            "tenant": "42",
+            "other": "meow",
+        }) else {
+            unreachable!()
+        };
+
+        mapping.remove_mapped(&mut extra);
+        assert_eq!(
+            serde_json::json!({"ssl": {"protocol": "TLSv1.3"}, "other": "meow"}),
+            Value::Object(extra)
+        );
+    }
+
+    #[test]
+    fn treats_missing_fields_as_null() {
+        let mapping = FieldMapping::parse(MAPPING).unwrap();
+        let (values, invalid_fields) = mapping.extract(&serde_json::json!({"req": "meow"}));
+        assert!(invalid_fields.is_empty());
+        assert_eq!(FieldValue::Text(None), values[0]);
+        assert_eq!(FieldValue::Bigint(None), values[2]);
+    }
+}
@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use metrics::{describe_counter, describe_gauge, describe_histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+
+// Counters
+pub const DATAGRAMS_RECEIVED: &str = "ngxslpg_datagrams_received_total";
+pub const BYTES_RECEIVED: &str = "ngxslpg_bytes_received_total";
+pub const UTF8_FAILURES: &str = "ngxslpg_utf8_failures_total";
+pub const PARSE_FAILURES: &str = "ngxslpg_parse_failures_total";
+pub const MISSING_FIELDS: &str = "ngxslpg_missing_fields_total";
+pub const INVALID_MAPPED_FIELDS: &str = "ngxslpg_invalid_mapped_fields_total";
+pub const AUTH_FAILURES: &str = "ngxslpg_auth_failures_total";
+pub const QUEUE_FULL_DROPS: &str = "ngxslpg_queue_full_drops_total";
+pub const SOURCE_DROPS: &str = "ngxslpg_source_drops_total";
+pub const SAMPLING_DROPS: &str = "ngxslpg_sampling_drops_total";
+pub const ROWS_INSERTED: &str = "ngxslpg_rows_inserted_total";
+pub const INSERT_FAILURES: &str = "ngxslpg_insert_failures_total";
+pub const INSERT_RETRIES: &str = "ngxslpg_insert_retries_total";
+pub const SPOOLED_DATAGRAMS: &str = "ngxslpg_spooled_datagrams_total";
+pub const SPOOL_REPLAYED_DATAGRAMS: &str = "ngxslpg_spool_replayed_datagrams_total";
+pub const SPOOL_EVICTED_SEGMENTS: &str = "ngxslpg_spool_evicted_segments_total";
+
+// Gauges
+pub const QUEUE_OCCUPANCY: &str = "ngxslpg_queue_occupancy";
+pub const SPOOL_SIZE: &str = "ngxslpg_spool_size_bytes";
+
+// Histograms
+pub const BATCH_SIZE: &str = "ngxslpg_batch_size";
+pub const INSERT_DURATION: &str = "ngxslpg_insert_duration_seconds";
+
+/// Starts an HTTP listener that serves all metrics in Prometheus' text format.
+/// If this never gets called, all metrics are no-ops.
+pub fn install_exporter(listen_addr: SocketAddr) -> Result<()> {
+    PrometheusBuilder::new()
+        .with_http_listener(listen_addr)
+        .set_buckets_for_metric(
+            Matcher::Full(BATCH_SIZE.to_owned()),
+            &[1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0],
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full(INSERT_DURATION.to_owned()),
+            &[
+                0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ],
+        )?
+        .install()?;
+
+    describe_counter!(DATAGRAMS_RECEIVED, "Datagrams received on the socket");
+    describe_counter!(BYTES_RECEIVED, "Bytes received on the socket");
+    describe_counter!(UTF8_FAILURES, "Datagrams that were not valid UTF-8");
+    describe_counter!(PARSE_FAILURES, "Datagrams that could not be parsed");
+    describe_counter!(
+        MISSING_FIELDS,
+        "Fields missing in access_log entries, by field"
+    );
+    describe_counter!(
+        INVALID_MAPPED_FIELDS,
+        "Mapped fields stored as NULL because their value could not be converted, by field"
+    );
+    describe_counter!(AUTH_FAILURES, "Datagrams that failed the AUTH_KEYS check");
+    describe_counter!(
+        QUEUE_FULL_DROPS,
+        "Datagrams dropped because the processing queue was full"
+    );
+    describe_counter!(
+        SOURCE_DROPS,
+        "Messages dropped because of ALLOWED_SOURCES or RATE_LIMIT, by reason"
+    );
+    describe_counter!(
+        SAMPLING_DROPS,
+        "access_log entries dropped by SAMPLING_RULES_FILE, by rule"
+    );
+    describe_counter!(ROWS_INSERTED, "Rows inserted into the database, by table");
+    describe_counter!(
+        INSERT_FAILURES,
+        "Batch inserts that failed, after all retries"
+    );
+    describe_counter!(INSERT_RETRIES, "Batch inserts that were retried");
+    describe_counter!(SPOOLED_DATAGRAMS, "Datagrams written to the on-disk spool");
+    describe_counter!(
+        SPOOL_REPLAYED_DATAGRAMS,
+        "Spooled datagrams that were replayed into the database"
+    );
+    describe_counter!(
+        SPOOL_EVICTED_SEGMENTS,
+        "Spool segments deleted because the spool was full"
+    );
+    describe_gauge!(
+        QUEUE_OCCUPANCY,
+        "Messages currently in the processing queue"
+    );
+    describe_gauge!(SPOOL_SIZE, "Size of the on-disk spool");
+    describe_histogram!(BATCH_SIZE, "Number of datagrams per insert batch");
+    describe_histogram!(INSERT_DURATION, "Time an INSERT query took, by table");
+
+    Ok(())
+}
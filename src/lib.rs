@@ -1,7 +1,24 @@
 mod access_log_column_vecs;
+mod auth;
 mod bridge;
+mod column_vecs;
+mod datagram;
+pub mod enrichment;
+mod error_log_column_vecs;
+pub mod field_mapping;
+pub mod instrumentation;
+mod mapped_column_vecs;
 pub mod parsers;
+mod rejected_datagram_column_vecs;
+mod retry_policy;
+pub mod sampling_rules;
 pub mod settings;
+mod source_filter;
+mod spool;
+mod tcp_framing;
+pub mod tls;
 
 pub use access_log_column_vecs::AccessLogColumnVecs;
-pub use bridge::Bridge;
+pub use bridge::{Bridge, SyslogSocket};
+pub use error_log_column_vecs::ErrorLogColumnVecs;
+pub use rejected_datagram_column_vecs::RejectedDatagramColumnVecs;
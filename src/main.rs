@@ -1,9 +1,15 @@
 use anyhow::{Result, bail};
 use clap::Parser;
 use sqlx::postgres::PgPoolOptions;
-use std::path::Path;
+use std::sync::Arc;
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tracing::{error, info};
 
-use nginx_syslog_postgres_bridge::{Bridge, settings::LogFormat, settings::Settings};
+use nginx_syslog_postgres_bridge::{
+    Bridge, SyslogSocket, instrumentation,
+    settings::{ListenAddr, LogFormat, Settings},
+    tls::{ReloadableTlsConfig, TlsFiles},
+};
 
 fn main() -> Result<()> {
     let settings = Settings::parse();
@@ -32,20 +38,49 @@ async fn run(settings: Settings) -> Result<()> {
         LogFormat::Json => subscriber.json().with_span_list(false).init(),
     }
 
+    if let Some(metrics_addr) = settings.metrics_addr {
+        instrumentation::install_exporter(metrics_addr)?;
+    }
+
     let settings_clone = settings.clone();
-    let socket = match settings_clone.listen_addr.strip_prefix("unix:") {
-        Some(path) => {
-            let path = Path::new(path);
-            if path.exists() {
-                tokio::fs::remove_file(path).await?;
+
+    // All `tls:` listeners share the same certificates, so this only gets
+    // loaded once, if it's needed at all.
+    let mut tls_config: Option<Arc<ReloadableTlsConfig>> = None;
+    let mut sockets = Vec::with_capacity(settings.listen_addr.len());
+    for listen_addr in &settings.listen_addr {
+        let socket = match listen_addr {
+            ListenAddr::Udp(addr) => tokio::net::UdpSocket::bind(addr).await?.into(),
+            ListenAddr::Tcp(addr) => tokio::net::TcpListener::bind(addr).await?.into(),
+            ListenAddr::Tls(addr) => {
+                let tls_config = match &tls_config {
+                    Some(tls_config) => tls_config.clone(),
+                    None => {
+                        let loaded = Arc::new(ReloadableTlsConfig::load(TlsFiles::from_settings(
+                            &settings,
+                        )?)?);
+                        tokio::spawn(reload_tls_config_on_sighup(
+                            signal(SignalKind::hangup())?,
+                            loaded.clone(),
+                        ));
+                        tls_config.insert(loaded).clone()
+                    }
+                };
+
+                SyslogSocket::Tls(tokio::net::TcpListener::bind(addr).await?, tls_config)
             }
+            ListenAddr::Unix(path) => {
+                if path.exists() {
+                    tokio::fs::remove_file(path).await?;
+                }
 
-            tokio::net::UnixDatagram::bind(path)?.into()
-        }
-        None => tokio::net::UdpSocket::bind(&settings_clone.listen_addr)
-            .await?
-            .into(),
-    };
+                tokio::net::UnixDatagram::bind(path)?.into()
+            }
+        };
+
+        info!("Listening on {}", listen_addr);
+        sockets.push(socket);
+    }
 
     let db_pool = PgPoolOptions::new()
         .max_connections(
@@ -59,5 +94,22 @@ async fn run(settings: Settings) -> Result<()> {
         .await?;
     sqlx::migrate!().run(&db_pool).await?;
 
-    Bridge::run(db_pool, settings, socket).await
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = sigterm.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+    };
+
+    Bridge::run(db_pool, settings, sockets, shutdown).await
+}
+
+async fn reload_tls_config_on_sighup(mut sighup: Signal, tls_config: Arc<ReloadableTlsConfig>) {
+    while sighup.recv().await.is_some() {
+        match tls_config.reload() {
+            Ok(()) => info!("Reloaded TLS certificates"),
+            Err(err) => error!("Reloading TLS certificates failed: {:?}", err),
+        }
+    }
 }
@@ -0,0 +1,82 @@
+use sqlx::{Postgres, postgres::PgArguments, query::Query};
+
+use crate::field_mapping::{FieldMapping, FieldType, FieldValue};
+
+/// The column vecs for the fields in FIELD_MAPPING_FILE. Since those are only
+/// known at runtime, the vecs can't be struct fields like in
+/// [crate::AccessLogColumnVecs], so each column is one entry in here, in the
+/// order of the mapping. These get bound after the [crate::AccessLogColumnVecs]
+/// columns in the same `access_log` INSERT.
+pub struct MappedColumnVecs {
+    columns: Vec<MappedColumn>,
+}
+
+enum MappedColumn {
+    Text(Vec<Option<String>>),
+    Integer(Vec<Option<i32>>),
+    Bigint(Vec<Option<i64>>),
+    Float(Vec<Option<f64>>),
+    Boolean(Vec<Option<bool>>),
+}
+
+impl MappedColumnVecs {
+    pub fn with_capacity(mapping: &FieldMapping, capacity: usize) -> Self {
+        let columns = mapping
+            .fields
+            .iter()
+            .map(|field| match field.field_type {
+                FieldType::Text => MappedColumn::Text(Vec::with_capacity(capacity)),
+                FieldType::Integer => MappedColumn::Integer(Vec::with_capacity(capacity)),
+                FieldType::Bigint => MappedColumn::Bigint(Vec::with_capacity(capacity)),
+                FieldType::Float => MappedColumn::Float(Vec::with_capacity(capacity)),
+                FieldType::Boolean => MappedColumn::Boolean(Vec::with_capacity(capacity)),
+            })
+            .collect();
+
+        Self { columns }
+    }
+
+    pub fn clear(&mut self) {
+        for column in &mut self.columns {
+            match column {
+                MappedColumn::Text(vec) => vec.clear(),
+                MappedColumn::Integer(vec) => vec.clear(),
+                MappedColumn::Bigint(vec) => vec.clear(),
+                MappedColumn::Float(vec) => vec.clear(),
+                MappedColumn::Boolean(vec) => vec.clear(),
+            }
+        }
+    }
+
+    /// Pushes the values extracted by [FieldMapping::extract] with the same
+    /// mapping, so there's exactly one value of the right type per column.
+    pub fn push(&mut self, values: Vec<FieldValue>) {
+        debug_assert_eq!(self.columns.len(), values.len());
+        for (column, value) in self.columns.iter_mut().zip(values) {
+            match (column, value) {
+                (MappedColumn::Text(vec), FieldValue::Text(value)) => vec.push(value),
+                (MappedColumn::Integer(vec), FieldValue::Integer(value)) => vec.push(value),
+                (MappedColumn::Bigint(vec), FieldValue::Bigint(value)) => vec.push(value),
+                (MappedColumn::Float(vec), FieldValue::Float(value)) => vec.push(value),
+                (MappedColumn::Boolean(vec), FieldValue::Boolean(value)) => vec.push(value),
+                _ => unreachable!("values were extracted with a different mapping"),
+            }
+        }
+    }
+
+    pub fn bind_all<'q>(
+        &'q self,
+        mut query: Query<'q, Postgres, PgArguments>,
+    ) -> Query<'q, Postgres, PgArguments> {
+        for column in &self.columns {
+            query = match column {
+                MappedColumn::Text(vec) => query.bind(vec),
+                MappedColumn::Integer(vec) => query.bind(vec),
+                MappedColumn::Bigint(vec) => query.bind(vec),
+                MappedColumn::Float(vec) => query.bind(vec),
+                MappedColumn::Boolean(vec) => query.bind(vec),
+            };
+        }
+        query
+    }
+}
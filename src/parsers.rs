@@ -1,4 +1,13 @@
 mod access_log_entry;
+mod combined_log_entry;
 mod deserializers;
+mod error_log_entry;
+mod log_entry;
+mod syslog_metadata;
+mod upstream_addr;
 
 pub use access_log_entry::AccessLogEntry;
+pub use error_log_entry::ErrorLogEntry;
+pub use log_entry::LogEntry;
+pub use syslog_metadata::SyslogMetadata;
+pub use upstream_addr::UpstreamAddr;
@@ -1,11 +1,57 @@
+use std::net::IpAddr;
+
+use anyhow::{Result, bail};
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
-
-use super::deserializers::*;
-
-#[derive(Debug, Deserialize)]
+use serde_json::{Map, Value};
+
+use super::{SyslogMetadata, deserializers::*};
+use crate::{enrichment::Enrichment, field_mapping::FieldValue};
+
+/// At least one of these has to be part of a JSON document for it to be an
+/// access_log entry, even with PARSING_MODE=lenient.
+const CORE_FIELDS: [&str; 3] = ["ts", "req", "res"];
+
+/// All fields nginx is expected to send with the log format in
+/// docs/nginx_config.md, with nested fields separated by dots.
+const EXPECTED_FIELDS: [&str; 27] = [
+    "hostname",
+    "ts",
+    "server.name",
+    "server.port",
+    "client.addr",
+    "client.forwarded_for",
+    "client.referer",
+    "client.ua",
+    "req.host",
+    "req.length",
+    "req.method",
+    "req.proto",
+    "req.scheme",
+    "req.uri",
+    "res.body_length",
+    "res.duration",
+    "res.length",
+    "res.status",
+    "upstream.addr",
+    "upstream.bytes_received",
+    "upstream.bytes_sent",
+    "upstream.cache_status",
+    "upstream.connect_time",
+    "upstream.host",
+    "upstream.response_length",
+    "upstream.response_time",
+    "upstream.status",
+];
+
+/// Every field is optional here, so that log lines from trimmed-down log
+/// formats can still be stored with PARSING_MODE=lenient. Whether fields are
+/// missing is checked separately with [AccessLogEntry::missing_fields].
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct AccessLogEntry {
-    pub hostname: String,
+    #[serde(deserialize_with = "optional_normalized_string")]
+    pub hostname: Option<String>,
 
     #[serde(deserialize_with = "datetime_from_mstimestamp")]
     pub ts: DateTime<Utc>,
@@ -19,21 +65,119 @@ pub struct AccessLogEntry {
     pub res: Res,
 
     pub upstream: Upstream,
+
+    /// Only used to check if the entry is authentic, if AUTH_KEYS is set.
+    #[serde(default)]
+    pub auth: Option<String>,
+
+    /// All fields that aren't known to the bridge. After
+    /// [AccessLogEntry::collect_extra], this also contains the unknown
+    /// fields of all sections, nested under the section's name.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+
+    #[serde(skip)]
+    pub syslog: SyslogMetadata,
+
+    /// The values of all fields in FIELD_MAPPING_FILE, in the same order.
+    #[serde(skip)]
+    pub mapped_fields: Vec<FieldValue>,
+
+    /// The expected fields that weren't part of the log line.
+    #[serde(skip)]
+    pub missing_fields: Vec<&'static str>,
+
+    #[serde(skip)]
+    pub enrichment: Enrichment,
+
+    /// The share of similar entries that got stored, according to
+    /// SAMPLING_RULES_FILE. `None` means all of them.
+    #[serde(skip)]
+    pub sample_rate: Option<f64>,
 }
 
-#[derive(Debug, Deserialize)]
+impl AccessLogEntry {
+    /// Deserializes an entry from a JSON document. Since all fields are
+    /// optional, documents that have none of the [CORE_FIELDS] are rejected,
+    /// so that any JSON object doesn't end up as an entry full of `NULL`s.
+    pub fn from_json(json: &Value) -> Result<Self> {
+        if !CORE_FIELDS.iter().any(|field| json.get(field).is_some()) {
+            bail!(
+                "missing fields `{}`, not an access_log entry",
+                CORE_FIELDS.join("`, `")
+            );
+        }
+
+        Ok(Self::deserialize(json)?)
+    }
+
+    /// Returns all expected fields that are missing in the JSON document. If
+    /// a whole section is missing, only the section gets listed, not each of
+    /// its fields.
+    pub fn missing_fields(json: &Value) -> Vec<&'static str> {
+        let mut missing = vec![];
+        for path in EXPECTED_FIELDS {
+            match path.split_once('.') {
+                None => {
+                    if json.get(path).is_none() {
+                        missing.push(path);
+                    }
+                }
+                Some((section, field)) => match json.get(section) {
+                    None => {
+                        if missing.last() != Some(&section) {
+                            missing.push(section);
+                        }
+                    }
+                    Some(section) => {
+                        if section.get(field).is_none() {
+                            missing.push(path);
+                        }
+                    }
+                },
+            }
+        }
+
+        missing
+    }
+
+    /// Moves the unknown fields of all sections into [AccessLogEntry::extra],
+    /// so they end up in the same JSON document as they were received in.
+    pub fn collect_extra(&mut self) {
+        let sections = [
+            ("server", &mut self.server.extra),
+            ("client", &mut self.client.extra),
+            ("req", &mut self.req.extra),
+            ("res", &mut self.res.extra),
+            ("upstream", &mut self.upstream.extra),
+        ];
+        for (section, extra) in sections {
+            if !extra.is_empty() {
+                self.extra
+                    .insert(section.to_owned(), Value::Object(std::mem::take(extra)));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct Server {
     #[serde(deserialize_with = "optional_normalized_string")]
     pub name: Option<String>,
 
     #[serde(deserialize_with = "optional_number_from_string")]
     pub port: Option<i32>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct Client {
     #[serde(deserialize_with = "optional_normalized_ip")]
-    pub addr: Option<String>,
+    pub addr: Option<IpAddr>,
 
     #[serde(deserialize_with = "optional_normalized_string")]
     pub forwarded_for: Option<String>,
@@ -43,9 +187,13 @@ pub struct Client {
 
     #[serde(deserialize_with = "optional_normalized_string")]
     pub ua: Option<String>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct Req {
     #[serde(deserialize_with = "optional_normalized_string")]
     pub host: Option<String>,
@@ -64,9 +212,13 @@ pub struct Req {
 
     #[serde(deserialize_with = "optional_normalized_string")]
     pub uri: Option<String>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct Res {
     #[serde(deserialize_with = "optional_number_from_string")]
     pub body_length: Option<i64>,
@@ -79,36 +231,45 @@ pub struct Res {
 
     #[serde(deserialize_with = "optional_number_from_string")]
     pub status: Option<i32>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Most of these contain one value per upstream server nginx tried, in
+/// order. Check [upstream_values] for details.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct Upstream {
-    #[serde(deserialize_with = "optional_normalized_ip")]
-    pub addr: Option<String>,
+    #[serde(deserialize_with = "upstream_values")]
+    pub addr: Vec<Option<String>>,
 
-    #[serde(deserialize_with = "optional_number_from_string")]
-    pub bytes_received: Option<i64>,
+    #[serde(deserialize_with = "upstream_values")]
+    pub bytes_received: Vec<Option<i64>>,
 
-    #[serde(deserialize_with = "optional_number_from_string")]
-    pub bytes_sent: Option<i64>,
+    #[serde(deserialize_with = "upstream_values")]
+    pub bytes_sent: Vec<Option<i64>>,
 
     #[serde(deserialize_with = "optional_normalized_string")]
     pub cache_status: Option<String>,
 
-    #[serde(deserialize_with = "optional_number_from_string")]
-    pub connect_time: Option<f64>,
+    #[serde(deserialize_with = "upstream_values")]
+    pub connect_time: Vec<Option<f64>>,
 
     #[serde(deserialize_with = "optional_normalized_string")]
     pub host: Option<String>,
 
-    #[serde(deserialize_with = "optional_number_from_string")]
-    pub response_length: Option<i64>,
+    #[serde(deserialize_with = "upstream_values")]
+    pub response_length: Vec<Option<i64>>,
 
-    #[serde(deserialize_with = "optional_number_from_string")]
-    pub response_time: Option<f64>,
+    #[serde(deserialize_with = "upstream_values")]
+    pub response_time: Vec<Option<f64>>,
 
-    #[serde(deserialize_with = "optional_number_from_string")]
-    pub status: Option<i32>,
+    #[serde(deserialize_with = "upstream_values")]
+    pub status: Vec<Option<i32>>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[cfg(test)]
@@ -136,10 +297,51 @@ mod test {
         assert!(deserialized.is_ok());
     }
 
+    #[test]
+    fn collects_unknown_fields() {
+        let json = r#"{"hostname":"1b2dd316acb5","ts":"1660345167.135","tenant":"meow","server":{"name":"_","port":"80"},"client":{"addr":"172.18.0.1","forwarded_for":"","referer":"","ua":""},"req":{"host":"localhost","id":"abc","length":"1616","method":"GET","proto":"HTTP/1.1","scheme":"http","uri":"/"},"res":{"body_length":"615","duration":"0.000","length":"853","status":"200"},"upstream":{"addr":"","bytes_received":"","bytes_sent":"","cache_status":"","connect_time":"","host":"","response_length":"","response_time":"","status":""}}"#;
+        let mut entry = serde_json::from_str::<AccessLogEntry>(json).unwrap();
+        entry.collect_extra();
+        assert_eq!(
+            serde_json::json!({"tenant": "meow", "req": {"id": "abc"}}),
+            Value::Object(entry.extra)
+        );
+    }
+
     #[test]
     fn is_err_for_junk() {
-        let json = r#"{"hello": "world"}"#;
+        let json = serde_json::from_str::<Value>(r#"{"hello":"world"}"#).unwrap();
+        assert!(AccessLogEntry::from_json(&json).is_err());
+
+        let json = r#"{"hostname": 42}"#;
         let deserialized = serde_json::from_str::<AccessLogEntry>(json);
         assert!(deserialized.is_err());
     }
+
+    #[test]
+    fn finds_no_missing_fields_in_complete_json() {
+        let json = r#"{"hostname":"1b2dd316acb5","ts":"1660345167.135","server":{"name":"_","port":"80"},"client":{"addr":"172.18.0.1","forwarded_for":"","referer":"http://localhost:8080/","ua":"Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:105.0) Gecko/20100101 Firefox/105.0"},"req":{"host":"localhost","length":"1616","method":"GET","proto":"HTTP/1.1","scheme":"http","uri":"/favicon.ico"},"res":{"body_length":"615","duration":"0.000","length":"853","status":"200"},"upstream":{"addr":"","bytes_received":"","bytes_sent":"","cache_status":"","connect_time":"","host":"","response_length":"","response_time":"","status":""}}"#;
+        let json = serde_json::from_str::<Value>(json).unwrap();
+        assert!(AccessLogEntry::missing_fields(&json).is_empty());
+    }
+
+    #[test]
+    fn finds_missing_fields_and_sections() {
+        let json = r#"{"ts":"1660345167.135","server":{"name":"_","port":"80"},"client":{"addr":"172.18.0.1"},"req":{"host":"localhost","length":"1616","method":"GET","proto":"HTTP/1.1","scheme":"http","uri":"/"},"res":{"body_length":"615","duration":"0.000","length":"853","status":"200"}}"#;
+        let json = serde_json::from_str::<Value>(json).unwrap();
+        assert_eq!(
+            vec![
+                "hostname",
+                "client.forwarded_for",
+                "client.referer",
+                "client.ua",
+                "upstream"
+            ],
+            AccessLogEntry::missing_fields(&json)
+        );
+
+        let entry = AccessLogEntry::from_json(&json).unwrap();
+        assert!(entry.hostname.is_none());
+        assert!(entry.upstream.status.is_empty());
+    }
 }
@@ -0,0 +1,210 @@
+use anyhow::{Context, Result, bail};
+use chrono::DateTime;
+
+use super::{
+    AccessLogEntry,
+    access_log_entry::{Client, Req, Res},
+    deserializers::normalize_ip,
+};
+
+impl AccessLogEntry {
+    /// Parses nginx' predefined `combined` log format, which looks like
+    /// `172.19.0.1 - - [16/Aug/2022:18:35:53 +0000] "GET / HTTP/1.1" 200 615 "-" "curl/8.0"`.
+    /// The `common` format, which is the same without the referer and user
+    /// agent, works as well. Everything else these formats don't include
+    /// stays empty.
+    pub fn from_combined(syslog: &syslog_loose::Message<&str>) -> Result<Self> {
+        let mut fields = CombinedFields {
+            remaining: syslog.msg,
+        };
+
+        let addr = fields.next_word().context("missing remote address")?;
+        fields.next_word().context("missing remote ident")?;
+        fields.next_word().context("missing remote user")?;
+
+        let time_local = fields
+            .next_delimited('[', ']')
+            .context("missing local time")?;
+        let ts = DateTime::parse_from_str(time_local, "%d/%b/%Y:%H:%M:%S %z")
+            .context("invalid local time")?
+            .to_utc();
+
+        let request = fields.next_quoted().context("missing request line")?;
+        let status = fields.next_word().context("missing status")?;
+        let body_bytes_sent = fields.next_word().context("missing body bytes sent")?;
+
+        // These only exist in the combined format.
+        let referer = fields.next_quoted();
+        let ua = fields.next_quoted();
+        if !fields.remaining.trim().is_empty() {
+            bail!("unexpected trailing data in combined log line");
+        }
+
+        // Request lines that aren't valid HTTP, like from port scanners, are
+        // still logged, so they're only split if they look like one.
+        let (method, uri, proto) = match request.splitn(3, ' ').collect::<Vec<_>>()[..] {
+            [method, uri, proto] => (Some(method), Some(uri), Some(proto)),
+            _ => (None, None, None),
+        };
+
+        Ok(Self {
+            // Same as for error_log entries, the syslog hostname is only a
+            // hostname if there's also a tag.
+            hostname: syslog.appname.and(syslog.hostname).map(str::to_owned),
+            ts,
+            client: Client {
+                addr: normalize_ip(addr).context("invalid remote address")?,
+                referer: not_empty(referer).map(unescape),
+                ua: not_empty(ua).map(unescape),
+                ..Default::default()
+            },
+            req: Req {
+                method: method.map(str::to_owned),
+                proto: proto.map(str::to_owned),
+                uri: uri.map(unescape),
+                ..Default::default()
+            },
+            res: Res {
+                body_length: Some(body_bytes_sent.parse().context("invalid body bytes sent")?),
+                status: Some(status.parse().context("invalid status")?),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+}
+
+/// nginx writes `-` for variables that aren't set.
+fn not_empty(value: Option<&str>) -> Option<&str> {
+    value.filter(|v| !v.is_empty() && *v != "-")
+}
+
+/// Without `escape=json`, nginx escapes `"`, `\`, and everything that isn't
+/// printable ASCII as `\xXX`. This turns those back into the original bytes.
+fn unescape(value: &str) -> String {
+    if !value.contains("\\x") {
+        return value.to_owned();
+    }
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut remaining = value.as_bytes();
+    while let Some((&byte, rest)) = remaining.split_first() {
+        let escaped = rest
+            .strip_prefix(b"x")
+            .and_then(|rest| rest.get(..2))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'\\', Some(unescaped)) => {
+                bytes.push(unescaped);
+                remaining = &rest[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                remaining = rest;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Splits a combined log line into its space-separated fields, some of which
+/// are wrapped in quotes or brackets.
+struct CombinedFields<'a> {
+    remaining: &'a str,
+}
+
+impl<'a> CombinedFields<'a> {
+    fn next_word(&mut self) -> Option<&'a str> {
+        let trimmed = self.remaining.trim_start_matches(' ');
+        if trimmed.is_empty() {
+            return None;
+        }
+
+        let (word, rest) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+        self.remaining = rest;
+        Some(word)
+    }
+
+    fn next_delimited(&mut self, start: char, end: char) -> Option<&'a str> {
+        let (value, rest) = self
+            .remaining
+            .trim_start_matches(' ')
+            .strip_prefix(start)?
+            .split_once(end)?;
+        self.remaining = rest;
+        Some(value)
+    }
+
+    /// Quotes inside values are always escaped by nginx, so the next quote
+    /// is always the end of the value.
+    fn next_quoted(&mut self) -> Option<&'a str> {
+        self.next_delimited('"', '"')
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(msg: &str) -> Result<AccessLogEntry> {
+        let syslog = syslog_loose::parse_message(msg, syslog_loose::Variant::Either);
+        AccessLogEntry::from_combined(&syslog)
+    }
+
+    #[test]
+    fn parses_combined_format() {
+        let entry = parse(
+            r#"<190>Aug 16 18:35:53 nginx: ::ffff:172.19.0.1 - alice [16/Aug/2022:18:35:53 +0200] "GET /search?q=\x22meow\x22 HTTP/1.1" 200 615 "http://localhost/" "curl/8.0""#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "2022-08-16T16:35:53Z",
+            entry.ts.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        );
+        assert_eq!(Some("172.19.0.1".parse().unwrap()), entry.client.addr);
+        assert_eq!(Some("http://localhost/".to_owned()), entry.client.referer);
+        assert_eq!(Some("curl/8.0".to_owned()), entry.client.ua);
+        assert_eq!(Some("GET".to_owned()), entry.req.method);
+        assert_eq!(Some("/search?q=\"meow\"".to_owned()), entry.req.uri);
+        assert_eq!(Some("HTTP/1.1".to_owned()), entry.req.proto);
+        assert_eq!(Some(200), entry.res.status);
+        assert_eq!(Some(615), entry.res.body_length);
+        assert!(entry.upstream.addr.is_empty());
+    }
+
+    #[test]
+    fn parses_common_format() {
+        let entry =
+            parse(r#"172.19.0.1 - - [16/Aug/2022:18:35:53 +0000] "GET / HTTP/1.1" 304 0"#).unwrap();
+        assert_eq!(Some(304), entry.res.status);
+        assert!(entry.client.referer.is_none());
+        assert!(entry.client.ua.is_none());
+    }
+
+    #[test]
+    fn keeps_invalid_request_lines() {
+        let entry =
+            parse(r#"172.19.0.1 - - [16/Aug/2022:18:35:53 +0000] "\x16\x03\x01" 400 157 "-" "-""#)
+                .unwrap();
+        assert_eq!(Some(400), entry.res.status);
+        assert!(entry.req.method.is_none());
+        assert!(entry.client.ua.is_none());
+    }
+
+    #[test]
+    fn is_err_for_other_formats() {
+        assert!(
+            parse(r#"2022/08/16 18:37:12 [error] 29#29: *3 open() "/usr/share/nginx/html/missing" failed"#)
+                .is_err()
+        );
+        assert!(
+            parse(
+                r#"172.19.0.1 - - [16/Aug/2022:18:35:53 +0000] "GET / HTTP/1.1" 200 0 "-" "-" meow"#
+            )
+            .is_err()
+        );
+    }
+}
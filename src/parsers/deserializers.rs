@@ -2,8 +2,10 @@ mod datetime_from_mstimestamp;
 mod optional_normalized_ip;
 mod optional_normalized_string;
 mod optional_number_from_string;
+mod upstream_values;
 
 pub use datetime_from_mstimestamp::datetime_from_mstimestamp;
-pub use optional_normalized_ip::optional_normalized_ip;
+pub use optional_normalized_ip::{normalize_ip, optional_normalized_ip};
 pub use optional_normalized_string::optional_normalized_string;
 pub use optional_number_from_string::optional_number_from_string;
+pub use upstream_values::upstream_values;
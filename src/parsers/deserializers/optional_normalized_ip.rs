@@ -1,17 +1,25 @@
+use std::net::{AddrParseError, IpAddr};
+
 use serde::{Deserialize, Deserializer};
 
-pub fn optional_normalized_ip<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+pub fn optional_normalized_ip<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let full_ip: String = Deserialize::deserialize(deserializer)?;
-    if full_ip.is_empty() {
-        Ok(None)
-    } else if let Some(stripped) = full_ip.strip_prefix("::ffff:") {
-        Ok(Some(stripped.to_owned()))
-    } else {
-        Ok(Some(full_ip))
+    let full_ip: &str = Deserialize::deserialize(deserializer)?;
+    normalize_ip(full_ip).map_err(serde::de::Error::custom)
+}
+
+/// Parses an address as nginx logs it in `$remote_addr`. IPv4 addresses on
+/// dual-stack sockets show up as IPv4-mapped IPv6 addresses, which get turned
+/// back into IPv4 addresses. Connections via unix sockets don't have an
+/// address, so they're treated like an empty value.
+pub fn normalize_ip(full_ip: &str) -> Result<Option<IpAddr>, AddrParseError> {
+    if full_ip.is_empty() || full_ip.starts_with("unix:") {
+        return Ok(None);
     }
+
+    full_ip.parse::<IpAddr>().map(|ip| Some(ip.to_canonical()))
 }
 
 #[cfg(test)]
@@ -23,34 +31,48 @@ mod test {
     #[derive(Debug, Deserialize)]
     struct TestStruct {
         #[serde(deserialize_with = "optional_normalized_ip")]
-        ip: Option<String>,
+        ip: Option<IpAddr>,
+    }
+
+    fn parse(ip: &str) -> Option<IpAddr> {
+        let json = format!(r#"{{"ip": "{}"}}"#, ip);
+        serde_json::from_str::<TestStruct>(&json).unwrap().ip
     }
 
     #[test]
     fn is_none_for_empty_string() {
-        let json = r#"{"ip": ""}"#;
-        let deserialized: TestStruct = serde_json::from_str(json).unwrap();
-        assert!(deserialized.ip.is_none());
+        assert!(parse("").is_none());
+    }
+
+    #[test]
+    fn is_none_for_unix_sockets() {
+        assert!(parse("unix:").is_none());
     }
 
     #[test]
     fn removes_prefix_from_ipv4_in_ipv6() {
-        let json = r#"{"ip": "::ffff:127.0.0.1"}"#;
-        let deserialized: TestStruct = serde_json::from_str(json).unwrap();
-        assert_eq!("127.0.0.1", deserialized.ip.unwrap());
+        assert_eq!(
+            Some("127.0.0.1".parse().unwrap()),
+            parse("::ffff:127.0.0.1")
+        );
     }
 
     #[test]
     fn leaves_ipv4_untouched() {
-        let json = r#"{"ip": "127.0.0.1"}"#;
-        let deserialized: TestStruct = serde_json::from_str(json).unwrap();
-        assert_eq!("127.0.0.1", deserialized.ip.unwrap());
+        assert_eq!(Some("127.0.0.1".parse().unwrap()), parse("127.0.0.1"));
     }
 
     #[test]
     fn leaves_ipv6_untouched() {
-        let json = r#"{"ip": "2001:db8::ffff"}"#;
-        let deserialized: TestStruct = serde_json::from_str(json).unwrap();
-        assert_eq!("2001:db8::ffff", deserialized.ip.unwrap());
+        assert_eq!(
+            Some("2001:db8::ffff".parse().unwrap()),
+            parse("2001:db8::ffff")
+        );
+    }
+
+    #[test]
+    fn is_err_for_junk() {
+        let json = r#"{"ip": "meow"}"#;
+        assert!(serde_json::from_str::<TestStruct>(json).is_err());
     }
 }
@@ -0,0 +1,97 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+/// If nginx tried more than one upstream server for a request, the
+/// `$upstream_*` variables contain one value per attempt, like `502, 200`.
+/// Values for servers of the same upstream group are separated by `, `, and
+/// values from different groups, like after an internal redirect, by ` : `.
+/// Attempts that didn't get that far, like a response time for a server
+/// that couldn't be connected to, are `-`.
+///
+/// This returns all attempts in order. It's empty if no upstream was used.
+pub fn upstream_values<'de, T, D>(deserializer: D) -> Result<Vec<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + serde::Deserialize<'de>,
+    <T as FromStr>::Err: std::fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Contents<'a, T> {
+        Str(&'a str),
+        FromStr(T),
+    }
+
+    let s = match Contents::<T>::deserialize(deserializer)? {
+        Contents::Str(s) => s,
+        Contents::FromStr(value) => return Ok(vec![Some(value)]),
+    };
+    if s.trim().is_empty() {
+        return Ok(vec![]);
+    }
+
+    s.split(" : ")
+        .flat_map(|group| group.split(", "))
+        .map(|value| match value.trim() {
+            "-" | "" => Ok(None),
+            value => T::from_str(value)
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct TestStruct {
+        #[serde(deserialize_with = "upstream_values")]
+        status: Vec<Option<i32>>,
+
+        #[serde(deserialize_with = "upstream_values")]
+        addr: Vec<Option<String>>,
+    }
+
+    #[test]
+    fn is_empty_for_empty_string() {
+        let json = r#"{"status": "", "addr": ""}"#;
+        let deserialized: TestStruct = serde_json::from_str(json).unwrap();
+        assert!(deserialized.status.is_empty());
+        assert!(deserialized.addr.is_empty());
+    }
+
+    #[test]
+    fn parses_single_value() {
+        let json = r#"{"status": "200", "addr": "93.184.216.34:80"}"#;
+        let deserialized: TestStruct = serde_json::from_str(json).unwrap();
+        assert_eq!(vec![Some(200)], deserialized.status);
+        assert_eq!(vec![Some("93.184.216.34:80".to_owned())], deserialized.addr);
+    }
+
+    #[test]
+    fn parses_multiple_attempts() {
+        let json = r#"{"status": "502, - : 200", "addr": "10.0.0.1:80, 10.0.0.2:80 : unix:/run/app.sock"}"#;
+        let deserialized: TestStruct = serde_json::from_str(json).unwrap();
+        assert_eq!(vec![Some(502), None, Some(200)], deserialized.status);
+        assert_eq!(
+            vec![
+                Some("10.0.0.1:80".to_owned()),
+                Some("10.0.0.2:80".to_owned()),
+                Some("unix:/run/app.sock".to_owned())
+            ],
+            deserialized.addr
+        );
+    }
+
+    #[test]
+    fn is_err_for_junk() {
+        let json = r#"{"status": "200, meow", "addr": ""}"#;
+        let deserialized = serde_json::from_str::<TestStruct>(json);
+        assert!(deserialized.is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use super::SyslogMetadata;
+
+/// All severity levels nginx knows for its `error_log`.
+const LEVELS: [&str; 8] = [
+    "debug", "info", "notice", "warn", "error", "crit", "alert", "emerg",
+];
+
+/// If an error happens while nginx is handling a request, it appends some
+/// context to the message, like `, client: 127.0.0.1, server: _`. These are
+/// all keys nginx uses for that, in the order they show up.
+const CONTEXT_KEYS: [&str; 7] = [
+    "client",
+    "server",
+    "request",
+    "subrequest",
+    "upstream",
+    "host",
+    "referrer",
+];
+
+#[derive(Debug)]
+pub struct ErrorLogEntry {
+    pub hostname: Option<String>,
+    pub ts: DateTime<Utc>,
+    pub level: String,
+    pub pid: Option<i32>,
+    pub tid: Option<i64>,
+    pub connection_id: Option<i64>,
+    pub message: String,
+    pub client: Option<String>,
+    pub server: Option<String>,
+    pub request: Option<String>,
+    pub subrequest: Option<String>,
+    pub upstream: Option<String>,
+    pub host: Option<String>,
+    pub referrer: Option<String>,
+    pub syslog: SyslogMetadata,
+}
+
+impl ErrorLogEntry {
+    /// Parses the syslog message nginx sends for `error_log` entries. They
+    /// look like
+    /// `2022/08/16 18:35:53 [error] 12#12: *1 open() "/x" failed (2: No such file or directory), client: 172.19.0.1, server: _, request: "GET /x HTTP/1.1", host: "localhost"`,
+    /// where everything from the connection id (`*1`) onwards is optional.
+    /// If the syslog header has no timestamp, `received_at` is used instead.
+    pub fn from_syslog(
+        syslog: &syslog_loose::Message<&str>,
+        received_at: DateTime<Utc>,
+    ) -> Result<Self> {
+        let msg = strip_timestamp(syslog.msg);
+
+        let Some(msg) = msg.strip_prefix('[') else {
+            bail!("message does not start with an error_log level");
+        };
+        let (level, msg) = msg.split_once("] ").context("missing error_log level")?;
+        if !LEVELS.contains(&level) {
+            bail!("unknown error_log level `{}`", level);
+        }
+
+        let (pid_tid, msg) = msg.split_once(": ").context("missing pid#tid")?;
+        let (pid, tid) = pid_tid.split_once('#').context("invalid pid#tid")?;
+
+        let (connection_id, msg) = match msg.strip_prefix('*').and_then(|m| m.split_once(' ')) {
+            Some((id, rest)) => match id.parse() {
+                Ok(id) => (Some(id), rest),
+                Err(_) => (None, msg),
+            },
+            None => (None, msg),
+        };
+
+        let (message, context) = match msg.find(", client: ") {
+            Some(idx) => (&msg[..idx], parse_context(&msg[idx + 2..])),
+            None => (msg, vec![]),
+        };
+        let context_value = |key: &str| {
+            context
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        };
+
+        Ok(Self {
+            // Without a hostname in the syslog header, syslog_loose reads
+            // nginx' tag as the hostname, and leaves the appname empty. So we
+            // only trust the hostname if there's also a tag.
+            hostname: syslog.appname.and(syslog.hostname).map(str::to_owned),
+            ts: syslog
+                .timestamp
+                .map(|ts| ts.to_utc())
+                .unwrap_or(received_at),
+            level: level.to_owned(),
+            pid: pid.parse().ok(),
+            tid: tid.parse().ok(),
+            connection_id,
+            message: message.to_owned(),
+            client: context_value("client"),
+            server: context_value("server"),
+            request: context_value("request"),
+            subrequest: context_value("subrequest"),
+            upstream: context_value("upstream"),
+            host: context_value("host"),
+            referrer: context_value("referrer"),
+            syslog: SyslogMetadata::default(),
+        })
+    }
+}
+
+/// nginx prefixes the message with its own local timestamp, like
+/// `2022/08/16 18:35:53`. We're using the syslog timestamp instead, so this
+/// just gets dropped if it exists.
+fn strip_timestamp(msg: &str) -> &str {
+    match (msg.get(..19), msg.get(19..20)) {
+        (Some(ts), Some(" ")) if NaiveDateTime::parse_from_str(ts, "%Y/%m/%d %H:%M:%S").is_ok() => {
+            &msg[20..]
+        }
+        _ => msg,
+    }
+}
+
+/// Splits `client: 127.0.0.1, server: _, request: "GET / HTTP/1.1"` into
+/// key-value pairs, with quotes removed. Values can contain anything,
+/// including `, `, so a value only ends where another known key begins.
+fn parse_context(context: &str) -> Vec<(&str, &str)> {
+    let mut result = vec![];
+    let mut remaining = context;
+
+    while let Some((key, rest)) = remaining.split_once(": ") {
+        let end = CONTEXT_KEYS
+            .iter()
+            .filter_map(|k| rest.find(&format!(", {}: ", k)))
+            .min()
+            .unwrap_or(rest.len());
+
+        let value = &rest[..end];
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        result.push((key, value));
+
+        remaining = rest.get(end + 2..).unwrap_or_default();
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(datagram: &str) -> Result<ErrorLogEntry> {
+        let syslog = syslog_loose::parse_message(datagram, syslog_loose::Variant::Either);
+        ErrorLogEntry::from_syslog(&syslog, Utc::now())
+    }
+
+    #[test]
+    fn parses_message_with_request_context() {
+        let entry = parse(
+            r#"<187>Aug 16 18:35:53 nginx: 2022/08/16 18:35:53 [error] 12#12: *1 open() "/usr/share/nginx/html/x" failed (2: No such file or directory), client: 172.19.0.1, server: _, request: "GET /x HTTP/1.1", host: "localhost""#,
+        )
+        .unwrap();
+
+        assert_eq!("error", entry.level);
+        assert_eq!(Some(12), entry.pid);
+        assert_eq!(Some(12), entry.tid);
+        assert_eq!(Some(1), entry.connection_id);
+        assert_eq!(
+            r#"open() "/usr/share/nginx/html/x" failed (2: No such file or directory)"#,
+            entry.message
+        );
+        assert_eq!(Some("172.19.0.1".to_owned()), entry.client);
+        assert_eq!(Some("_".to_owned()), entry.server);
+        assert_eq!(Some("GET /x HTTP/1.1".to_owned()), entry.request);
+        assert_eq!(Some("localhost".to_owned()), entry.host);
+        assert!(entry.subrequest.is_none());
+        assert!(entry.upstream.is_none());
+        assert!(entry.referrer.is_none());
+    }
+
+    #[test]
+    fn parses_upstream_and_referrer() {
+        let entry = parse(
+            r#"<187>Aug 16 18:35:53 nginx: 2022/08/16 18:35:53 [error] 12#12: *7 connect() failed (111: Connection refused) while connecting to upstream, client: 172.19.0.1, server: _, request: "GET /a, b HTTP/1.1", upstream: "http://127.0.0.1:8080/a, b", host: "localhost", referrer: "http://localhost/""#,
+        )
+        .unwrap();
+
+        assert_eq!(Some("GET /a, b HTTP/1.1".to_owned()), entry.request);
+        assert_eq!(
+            Some("http://127.0.0.1:8080/a, b".to_owned()),
+            entry.upstream
+        );
+        assert_eq!(Some("http://localhost/".to_owned()), entry.referrer);
+    }
+
+    #[test]
+    fn parses_subrequest() {
+        let entry = parse(
+            r#"<187>Aug 16 18:35:53 nginx: 2022/08/16 18:35:53 [error] 12#12: *3 auth request unexpected status: 500 while sending to client, client: 172.19.0.1, server: _, request: "GET /private HTTP/1.1", subrequest: "/auth", host: "localhost""#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "auth request unexpected status: 500 while sending to client",
+            entry.message
+        );
+        assert_eq!(Some("GET /private HTTP/1.1".to_owned()), entry.request);
+        assert_eq!(Some("/auth".to_owned()), entry.subrequest);
+        assert_eq!(Some("localhost".to_owned()), entry.host);
+    }
+
+    #[test]
+    fn parses_message_without_connection() {
+        let entry = parse(
+            r#"<189>Aug 16 18:35:53 nginx: 2022/08/16 18:35:53 [notice] 1#1: signal process started"#,
+        )
+        .unwrap();
+
+        assert_eq!("notice", entry.level);
+        assert!(entry.connection_id.is_none());
+        assert_eq!("signal process started", entry.message);
+        assert!(entry.client.is_none());
+    }
+
+    #[test]
+    fn uses_syslog_hostname_only_if_tag_exists() {
+        let entry = parse(r#"<187>Aug 16 18:35:53 nginx: [warn] 1#1: hello"#).unwrap();
+        assert!(entry.hostname.is_none());
+
+        let entry = parse(r#"<187>Aug 16 18:35:53 web01 nginx: [warn] 1#1: hello"#).unwrap();
+        assert_eq!(Some("web01".to_owned()), entry.hostname);
+    }
+
+    #[test]
+    fn uses_received_at_without_syslog_timestamp() {
+        let syslog = syslog_loose::parse_message(
+            "<187>1 - web01 nginx - - - [warn] 1#1: hello",
+            syslog_loose::Variant::Either,
+        );
+        let received_at = "2022-08-16T18:35:53Z".parse().unwrap();
+        let entry = ErrorLogEntry::from_syslog(&syslog, received_at).unwrap();
+
+        assert_eq!(received_at, entry.ts);
+    }
+
+    #[test]
+    fn is_err_for_access_log_json() {
+        assert!(parse(r#"<190>Aug 16 18:35:53 nginx: {"hello": "world"}"#).is_err());
+    }
+
+    #[test]
+    fn is_err_for_unknown_level() {
+        assert!(parse(r#"<187>Aug 16 18:35:53 nginx: [meow] 1#1: hello"#).is_err());
+    }
+}
@@ -0,0 +1,25 @@
+use super::{AccessLogEntry, ErrorLogEntry};
+
+/// nginx can send both `access_log` and `error_log` to the bridge. Both end up
+/// in their own table, so this just carries whichever one was received.
+///
+/// Entries only live for as long as it takes to push them into their column
+/// vecs, so boxing the larger variant would just cost an extra allocation.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum LogEntry {
+    Access(AccessLogEntry),
+    Error(ErrorLogEntry),
+}
+
+impl From<AccessLogEntry> for LogEntry {
+    fn from(value: AccessLogEntry) -> Self {
+        Self::Access(value)
+    }
+}
+
+impl From<ErrorLogEntry> for LogEntry {
+    fn from(value: ErrorLogEntry) -> Self {
+        Self::Error(value)
+    }
+}
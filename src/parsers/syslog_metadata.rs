@@ -0,0 +1,81 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+
+/// Everything the syslog header and the transport know about a message, as
+/// opposed to what nginx put into the message itself.
+#[derive(Debug, Default)]
+pub struct SyslogMetadata {
+    pub facility: Option<String>,
+    pub severity: Option<String>,
+    pub tag: Option<String>,
+    pub ts: Option<DateTime<Utc>>,
+    pub source_addr: Option<IpAddr>,
+}
+
+impl SyslogMetadata {
+    pub fn new(syslog: &syslog_loose::Message<&str>, source_addr: Option<IpAddr>) -> Self {
+        Self {
+            facility: syslog.facility.map(|f| f.as_str().to_owned()),
+            severity: syslog.severity.map(|s| s.as_str().to_owned()),
+            // With `nohostname`, nginx only sends a tag, which syslog_loose
+            // then reads as the hostname. nginx always sends a tag, so a
+            // "hostname" without an appname has to be the tag. RFC5424
+            // headers have separate fields for both, so there, a hostname is
+            // always a hostname.
+            tag: match syslog.protocol {
+                syslog_loose::Protocol::RFC3164 => syslog.appname.or(syslog.hostname),
+                syslog_loose::Protocol::RFC5424(_) => syslog.appname,
+            }
+            .map(str::to_owned),
+            ts: syslog.timestamp.map(|ts| ts.to_utc()),
+            source_addr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(datagram: &str) -> SyslogMetadata {
+        let syslog = syslog_loose::parse_message(datagram, syslog_loose::Variant::Either);
+        SyslogMetadata::new(&syslog, None)
+    }
+
+    #[test]
+    fn reads_facility_and_severity() {
+        let metadata = parse(r#"<190>Aug 16 18:35:53 nginx: {}"#);
+        assert_eq!(Some("local7".to_owned()), metadata.facility);
+        assert_eq!(Some("info".to_owned()), metadata.severity);
+    }
+
+    #[test]
+    fn reads_tag_without_hostname() {
+        let metadata = parse(r#"<190>Aug 16 18:35:53 nginx_static: {}"#);
+        assert_eq!(Some("nginx_static".to_owned()), metadata.tag);
+    }
+
+    #[test]
+    fn reads_tag_with_hostname() {
+        let metadata = parse(r#"<190>Aug 16 18:35:53 web01 nginx_static: {}"#);
+        assert_eq!(Some("nginx_static".to_owned()), metadata.tag);
+    }
+
+    #[test]
+    fn does_not_read_rfc5424_hostname_as_tag() {
+        let metadata = parse(r#"<190>1 2022-08-16T18:35:53Z web01 - - - - {}"#);
+        assert!(metadata.tag.is_none());
+
+        let metadata = parse(r#"<190>1 2022-08-16T18:35:53Z web01 nginx_static - - - {}"#);
+        assert_eq!(Some("nginx_static".to_owned()), metadata.tag);
+    }
+
+    #[test]
+    fn is_empty_without_syslog_header() {
+        let metadata = parse(r#"{}"#);
+        assert!(metadata.facility.is_none());
+        assert!(metadata.tag.is_none());
+        assert!(metadata.ts.is_none());
+    }
+}
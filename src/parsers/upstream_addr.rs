@@ -0,0 +1,76 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// One address from `$upstream_addr`. nginx logs upstream servers either as
+/// socket addresses, like `[2001:db8::1]:80`, or as unix socket paths, like
+/// `unix:/run/app.sock`.
+#[derive(Debug, Default, PartialEq)]
+pub struct UpstreamAddr {
+    pub ip: Option<IpAddr>,
+    pub port: Option<i32>,
+    pub is_unix: bool,
+}
+
+impl UpstreamAddr {
+    /// Anything nginx logs here should be one of the above, but if it's not,
+    /// like if it logged the name of an upstream that couldn't be resolved,
+    /// that's not a reason to drop the whole log line, so it's just empty.
+    pub fn parse(addr: &str) -> Self {
+        if addr.starts_with("unix:") {
+            return Self {
+                is_unix: true,
+                ..Default::default()
+            };
+        }
+
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => Self {
+                ip: Some(addr.ip().to_canonical()),
+                port: Some(i32::from(addr.port())),
+                is_unix: false,
+            },
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_socket_addresses() {
+        assert_eq!(
+            UpstreamAddr {
+                ip: Some("93.184.216.34".parse().unwrap()),
+                port: Some(80),
+                is_unix: false,
+            },
+            UpstreamAddr::parse("93.184.216.34:80")
+        );
+        assert_eq!(
+            UpstreamAddr {
+                ip: Some("2001:db8::1".parse().unwrap()),
+                port: Some(8080),
+                is_unix: false,
+            },
+            UpstreamAddr::parse("[2001:db8::1]:8080")
+        );
+    }
+
+    #[test]
+    fn parses_unix_sockets() {
+        assert_eq!(
+            UpstreamAddr {
+                ip: None,
+                port: None,
+                is_unix: true,
+            },
+            UpstreamAddr::parse("unix:/run/app.sock")
+        );
+    }
+
+    #[test]
+    fn is_empty_for_junk() {
+        assert_eq!(UpstreamAddr::default(), UpstreamAddr::parse("backend"));
+    }
+}
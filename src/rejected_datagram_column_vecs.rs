@@ -0,0 +1,53 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use sqlx::{Postgres, postgres::PgArguments, query::Query};
+use uuid::Uuid;
+
+use crate::{
+    auth::redact_token,
+    column_vecs::{column_vecs_impl, column_vecs_push_body},
+    datagram::Datagram,
+};
+
+/// The `rejected_datagram` counterpart to [crate::AccessLogColumnVecs].
+pub struct RejectedDatagramColumnVecs {
+    pub id: Vec<Uuid>,
+    pub received_at: Vec<DateTime<Utc>>,
+    pub source_addr: Vec<Option<IpAddr>>,
+    pub payload: Vec<Vec<u8>>,
+    pub payload_text: Vec<String>,
+    pub error: Vec<String>,
+}
+
+column_vecs_impl! {
+    RejectedDatagramColumnVecs {
+        id => id::uuid,
+        received_at => received_ts::timestamptz,
+        source_addr => source_addr::inet,
+        payload => payload::bytea,
+        payload_text => payload_text::text,
+        error => error::text,
+    }
+}
+
+impl RejectedDatagramColumnVecs {
+    pub fn push(&mut self, datagram: &Datagram, error: String) {
+        let payload = match &datagram.payload {
+            Ok(payload) => payload.as_bytes(),
+            Err(err) => err.as_bytes(),
+        };
+        let payload = redact_token(payload);
+
+        column_vecs_push_body!(self, datagram, {
+            id: Uuid::new_v4(),
+            received_at: datagram.received_at,
+            source_addr: datagram.source_addr.map(|a| a.ip()),
+            payload: payload.to_vec(),
+            // PostgreSQL does not allow NUL in text columns. The raw payload
+            // is stored anyway, so that's fine to replace.
+            payload_text: String::from_utf8_lossy(&payload).replace('\0', "\u{FFFD}"),
+            error: error,
+        });
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use crate::settings::Settings;
+
+/// Decides if, and when, a failed batch insert should be tried again. Delays
+/// start at `initial_backoff` and double with every attempt.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub deadline: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_attempts: settings.insert_retry_attempts,
+            initial_backoff: Duration::from_millis(settings.insert_retry_backoff),
+            deadline: Duration::from_millis(settings.insert_retry_deadline),
+        }
+    }
+
+    /// Returns how long to wait before the next attempt, or [None] if the
+    /// error isn't worth retrying or we're out of attempts or time. `attempt`
+    /// is the number of retries that already happened, `elapsed` is the time
+    /// since the first attempt started.
+    pub fn next_delay(
+        &self,
+        attempt: u32,
+        elapsed: Duration,
+        err: &sqlx::Error,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts || !is_transient(err) {
+            return None;
+        }
+
+        let delay = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt));
+        if elapsed + delay > self.deadline {
+            return None;
+        }
+
+        Some(delay)
+    }
+}
+
+/// Errors that are likely to go away on their own, like the database
+/// restarting or failing over, or a conflict with another transaction.
+pub fn is_transient(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(db_err) => db_err.code().is_some_and(|code| {
+            // Class 08 covers all connection exceptions.
+            code.starts_with("08")
+                || matches!(
+                    code.as_ref(),
+                    // serialization_failure, deadlock_detected
+                    "40001" | "40P01"
+                    // too_many_connections
+                    | "53300"
+                    // admin_shutdown, crash_shutdown, cannot_connect_now
+                    | "57P01" | "57P02" | "57P03"
+                )
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            deadline: Duration::from_secs(1),
+        }
+    }
+
+    fn io_error() -> sqlx::Error {
+        sqlx::Error::Io(std::io::ErrorKind::ConnectionRefused.into())
+    }
+
+    #[test]
+    fn doubles_delay_with_every_attempt() {
+        let policy = policy();
+        let elapsed = Duration::ZERO;
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            policy.next_delay(0, elapsed, &io_error())
+        );
+        assert_eq!(
+            Some(Duration::from_millis(200)),
+            policy.next_delay(1, elapsed, &io_error())
+        );
+        assert_eq!(
+            Some(Duration::from_millis(400)),
+            policy.next_delay(2, elapsed, &io_error())
+        );
+    }
+
+    #[test]
+    fn stops_after_max_attempts() {
+        assert!(
+            policy()
+                .next_delay(3, Duration::ZERO, &io_error())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn stops_if_deadline_would_be_exceeded() {
+        let elapsed = Duration::from_millis(950);
+        assert!(policy().next_delay(0, elapsed, &io_error()).is_none());
+    }
+
+    #[test]
+    fn retries_pool_timeouts() {
+        let err = sqlx::Error::PoolTimedOut;
+        assert!(policy().next_delay(0, Duration::ZERO, &err).is_some());
+    }
+
+    #[test]
+    fn does_not_retry_permanent_errors() {
+        let err = sqlx::Error::ColumnNotFound("meow".to_owned());
+        assert!(policy().next_delay(0, Duration::ZERO, &err).is_none());
+    }
+}
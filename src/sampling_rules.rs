@@ -0,0 +1,371 @@
+use std::{ops::RangeInclusive, path::Path};
+
+use anyhow::{Context, Result, bail};
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{parsers::AccessLogEntry, settings::Settings};
+
+/// What happens to entries that match a rule.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Keep,
+    Drop,
+}
+
+/// One `[[rule]]` as it's written in SAMPLING_RULES_FILE. All conditions
+/// that are set have to match for the rule to match.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: Option<String>,
+
+    #[serde(default)]
+    hosts: Vec<String>,
+
+    path_prefix: Option<String>,
+
+    path_regex: Option<String>,
+
+    /// A single status code, like `404`, or an inclusive range, like
+    /// `500-599`.
+    status: Option<String>,
+
+    #[serde(default)]
+    methods: Vec<String>,
+
+    is_bot: Option<bool>,
+
+    #[serde(default)]
+    action: Action,
+
+    sample_rate: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    hosts: Vec<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    status: Option<RangeInclusive<i32>>,
+    methods: Vec<String>,
+    is_bot: Option<bool>,
+    sample_rate: f64,
+}
+
+impl Rule {
+    fn from_config(config: RuleConfig, index: usize) -> Result<Self> {
+        let name = config.name.unwrap_or_else(|| format!("#{}", index + 1));
+
+        let sample_rate = match (config.action, config.sample_rate) {
+            (Action::Keep, None) => 1.0,
+            (Action::Keep, Some(rate)) if (0.0..=1.0).contains(&rate) => rate,
+            (Action::Keep, Some(rate)) => {
+                bail!(
+                    "rule `{}`: sample_rate {} is not between 0 and 1",
+                    name,
+                    rate
+                )
+            }
+            (Action::Drop, None) => 0.0,
+            (Action::Drop, Some(_)) => {
+                bail!(
+                    "rule `{}`: sample_rate can't be used with action = \"drop\"",
+                    name
+                )
+            }
+        };
+
+        let path_regex = config
+            .path_regex
+            .map(|regex| Regex::new(&regex))
+            .transpose()
+            .with_context(|| format!("rule `{}`: invalid path_regex", name))?;
+        let status = config
+            .status
+            .map(|status| parse_status(&status))
+            .transpose()
+            .with_context(|| format!("rule `{}`: invalid status", name))?;
+
+        Ok(Self {
+            hosts: config
+                .hosts
+                .iter()
+                .map(|host| host.to_lowercase())
+                .collect(),
+            path_prefix: config.path_prefix,
+            path_regex,
+            status,
+            methods: config
+                .methods
+                .iter()
+                .map(|method| method.to_uppercase())
+                .collect(),
+            is_bot: config.is_bot,
+            sample_rate,
+            name,
+        })
+    }
+
+    /// Conditions on fields that are missing in the entry never match.
+    fn matches(&self, entry: &AccessLogEntry) -> bool {
+        let path = entry.enrichment.req_path.as_deref();
+
+        (self.hosts.is_empty()
+            || entry
+                .req
+                .host
+                .as_deref()
+                .is_some_and(|host| self.hosts.iter().any(|h| h.eq_ignore_ascii_case(host))))
+            && self
+                .path_prefix
+                .as_deref()
+                .is_none_or(|prefix| path.is_some_and(|path| path.starts_with(prefix)))
+            && self
+                .path_regex
+                .as_ref()
+                .is_none_or(|regex| path.is_some_and(|path| regex.is_match(path)))
+            && self
+                .status
+                .as_ref()
+                .is_none_or(|range| entry.res.status.is_some_and(|s| range.contains(&s)))
+            && (self.methods.is_empty()
+                || entry
+                    .req
+                    .method
+                    .as_deref()
+                    .is_some_and(|method| self.methods.iter().any(|m| m == method)))
+            && self
+                .is_bot
+                .is_none_or(|is_bot| entry.enrichment.user_agent.is_bot == Some(is_bot))
+    }
+}
+
+/// The result of evaluating the rules for one entry.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision {
+    /// The entry gets stored. `sample_rate` is the share of matching entries
+    /// that are kept, so counts can be re-weighted with `1 / sample_rate`.
+    Keep { sample_rate: f64 },
+
+    /// The entry gets dropped because of the rule with that name.
+    Drop { rule: String },
+}
+
+/// Decides which access_log entries get stored, configured in
+/// SAMPLING_RULES_FILE. Rules are checked in order, and the first matching
+/// one wins. Entries that don't match any rule are always kept.
+#[derive(Debug, Default)]
+pub struct SamplingRules {
+    rules: Vec<Rule>,
+}
+
+impl SamplingRules {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let rules = match &settings.sampling_rules_file {
+            Some(path) => Self::load(path)?,
+            None => Self::default(),
+        };
+
+        if rules.rules.iter().any(|rule| rule.is_bot.is_some()) && !settings.parse_user_agents {
+            bail!("sampling rules with is_bot need PARSE_USER_AGENTS");
+        }
+
+        if !rules.rules.is_empty() {
+            info!("Using {} sampling rules", rules.rules.len());
+        }
+
+        Ok(rules)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn parse(toml: &str) -> Result<Self> {
+        let file: RulesFile = toml::from_str(toml)?;
+        let rules = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(index, config)| Rule::from_config(config, index))
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// `payload` is the raw log line. Sampling is based on its hash instead
+    /// of a random number, so the same line always gets the same decision,
+    /// no matter which instance of the bridge sees it, or if it's replayed
+    /// from the spool.
+    pub fn evaluate(&self, entry: &AccessLogEntry, payload: &str) -> Decision {
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(entry)) else {
+            return Decision::Keep { sample_rate: 1.0 };
+        };
+
+        if rule.sample_rate >= 1.0 || (rule.sample_rate > 0.0 && sample(payload) < rule.sample_rate)
+        {
+            Decision::Keep {
+                sample_rate: rule.sample_rate,
+            }
+        } else {
+            Decision::Drop {
+                rule: rule.name.clone(),
+            }
+        }
+    }
+}
+
+/// Maps the payload to a number in `[0, 1)`.
+fn sample(payload: &str) -> f64 {
+    let hash = Sha256::digest(payload.as_bytes());
+    let value = u64::from_be_bytes(hash[..8].try_into().expect("hash to have 8 bytes"));
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn parse_status(status: &str) -> Result<RangeInclusive<i32>> {
+    let (start, end) = status.split_once('-').unwrap_or((status, status));
+    let range = start.trim().parse()?..=end.trim().parse()?;
+    if range.is_empty() {
+        bail!("`{}` is an empty range", status);
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(host: &str, method: &str, path: &str, status: i32) -> AccessLogEntry {
+        let mut entry = AccessLogEntry::default();
+        entry.req.host = Some(host.to_owned());
+        entry.req.method = Some(method.to_owned());
+        entry.res.status = Some(status);
+        entry.enrichment.req_path = Some(path.to_owned());
+        entry
+    }
+
+    #[test]
+    fn keeps_everything_without_rules() {
+        assert_eq!(
+            Decision::Keep { sample_rate: 1.0 },
+            SamplingRules::parse("")
+                .unwrap()
+                .evaluate(&entry("example.com", "GET", "/", 200), "meow")
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = SamplingRules::parse(
+            r#"
+            [[rule]]
+            name = "errors"
+            status = "500-599"
+
+            [[rule]]
+            name = "health"
+            hosts = ["Example.com"]
+            path_prefix = "/health"
+            methods = ["get", "head"]
+            action = "drop"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Decision::Keep { sample_rate: 1.0 },
+            rules.evaluate(&entry("example.com", "GET", "/healthz", 503), "meow")
+        );
+        assert_eq!(
+            Decision::Drop {
+                rule: "health".to_owned()
+            },
+            rules.evaluate(&entry("example.com", "HEAD", "/healthz", 200), "meow")
+        );
+        assert_eq!(
+            Decision::Keep { sample_rate: 1.0 },
+            rules.evaluate(&entry("example.com", "POST", "/healthz", 200), "meow")
+        );
+        assert_eq!(
+            Decision::Keep { sample_rate: 1.0 },
+            rules.evaluate(&entry("example.org", "GET", "/healthz", 200), "meow")
+        );
+    }
+
+    #[test]
+    fn matches_regexes_and_bots() {
+        let rules = SamplingRules::parse(
+            r#"
+            [[rule]]
+            path_regex = '\.(css|js)$'
+            is_bot = true
+            action = "drop"
+            "#,
+        )
+        .unwrap();
+
+        let mut bot = entry("example.com", "GET", "/app.js", 200);
+        bot.enrichment.user_agent.is_bot = Some(true);
+        assert_eq!(
+            Decision::Drop {
+                rule: "#1".to_owned()
+            },
+            rules.evaluate(&bot, "meow")
+        );
+
+        // Without user agent parsing, `is_bot` is unknown, which never
+        // matches.
+        assert_eq!(
+            Decision::Keep { sample_rate: 1.0 },
+            rules.evaluate(&entry("example.com", "GET", "/app.js", 200), "meow")
+        );
+    }
+
+    #[test]
+    fn samples_deterministically() {
+        let rules = SamplingRules::parse(
+            r#"
+            [[rule]]
+            path_prefix = "/static/"
+            status = "200"
+            sample_rate = 0.1
+            "#,
+        )
+        .unwrap();
+
+        let entry = entry("example.com", "GET", "/static/app.js", 200);
+        let kept = (0..10_000)
+            .filter(|i| {
+                let payload = format!("line {}", i);
+                let decision = rules.evaluate(&entry, &payload);
+                assert_eq!(decision, rules.evaluate(&entry, &payload));
+                decision == Decision::Keep { sample_rate: 0.1 }
+            })
+            .count();
+        assert!((900..1100).contains(&kept), "kept {} of 10000", kept);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(SamplingRules::parse("[[rule]]\nsample_rate = 1.5").is_err());
+        assert!(SamplingRules::parse("[[rule]]\naction = \"drop\"\nsample_rate = 0.5").is_err());
+        assert!(SamplingRules::parse("[[rule]]\nstatus = \"599-500\"").is_err());
+        assert!(SamplingRules::parse("[[rule]]\nstatus = \"5xx\"").is_err());
+        assert!(SamplingRules::parse("[[rule]]\npath_regex = \"(\"").is_err());
+        assert!(SamplingRules::parse("[[rule]]\nuri = \"/\"").is_err());
+    }
+}
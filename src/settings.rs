@@ -1,3 +1,11 @@
+use std::{
+    fmt,
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use ipnet::IpNet;
 use sqlx::postgres::PgConnectOptions;
 
 /// Specifies the log's output format
@@ -32,19 +40,146 @@ impl LogLevel {
     }
 }
 
+/// Specifies how client addresses get anonymized before they're stored
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+pub enum IpAnonymization {
+    /// Addresses are stored as they are
+    None,
+    /// IPv4 addresses are truncated to /24, IPv6 addresses to /48
+    Truncate,
+    /// Addresses are replaced with a pseudonym that's derived from
+    /// IP_ANONYMIZATION_KEY and a random salt, which changes every day
+    Hmac,
+}
+
+/// Specifies what happens to `access_log` entries with missing fields
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+pub enum ParsingMode {
+    /// Entries with missing fields are rejected
+    Strict,
+    /// Missing fields are stored as NULL
+    Lenient,
+}
+
+/// One of the addresses the server listens on. Everything without a known
+/// prefix is a UDP socket address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Udp(String),
+    Tcp(String),
+    Tls(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("listen address must not be empty".to_owned());
+        }
+
+        Ok(if let Some(addr) = s.strip_prefix("tcp:") {
+            Self::Tcp(validate_socket_addr(addr)?)
+        } else if let Some(addr) = s.strip_prefix("tls:") {
+            Self::Tls(validate_socket_addr(addr)?)
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            Self::Unix(path.into())
+        } else {
+            Self::Udp(validate_socket_addr(s)?)
+        })
+    }
+}
+
+/// Makes sure a socket address can be bound to later. Host names, like
+/// `localhost:8514`, are allowed, as long as they resolve.
+fn validate_socket_addr(addr: &str) -> Result<String, String> {
+    match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(_)) => Ok(addr.to_owned()),
+        Ok(None) => Err(format!("`{}` does not resolve to any address", addr)),
+        Err(err) => Err(format!("`{}` is not a valid socket address: {}", addr, err)),
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Udp(addr) => write!(f, "{}", addr),
+            ListenAddr::Tcp(addr) => write!(f, "tcp:{}", addr),
+            ListenAddr::Tls(addr) => write!(f, "tls:{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
 #[derive(Clone, Debug, clap::Parser)]
 #[clap(about, version, propagate_version = true)]
 pub struct Settings {
+    /// A comma-separated list of networks in CIDR notation, like
+    /// `10.0.0.0/8,2001:db8::/32`. If set, messages from all other addresses
+    /// get dropped. Messages received on unix sockets are always accepted.
+    #[clap(long, env = "ALLOWED_SOURCES", value_delimiter = ',')]
+    pub allowed_sources: Vec<IpNet>,
+
+    /// A comma-separated list of shared secrets. If set, messages have to be
+    /// signed with, or contain, one of them. Check the README for details.
+    #[clap(long, env = "AUTH_KEYS", value_delimiter = ',', hide_env_values = true)]
+    pub auth_keys: Vec<String>,
+
+    /// A comma-separated list of syslog tags whose messages are in nginx'
+    /// `combined` or `common` log format. Messages with these tags that aren't
+    /// JSON get rejected if they don't match that format. Messages with other
+    /// tags are checked for that format as well, before falling back to
+    /// `error_log`.
+    #[clap(long, env = "COMBINED_LOG_TAGS", value_delimiter = ',')]
+    pub combined_log_tags: Vec<String>,
+
     /// The database URL to connect to. Needs to be a valid libpq
     /// connection URL, like `postgres://postgres@127.0.0.1/nginx_logs`
     #[clap(long, env = "DATABASE_URL")]
     pub database_url: PgConnectOptions,
 
+    /// Path to a TOML file that maps additional fields from custom nginx log
+    /// formats to their own columns in `access_log`. Check the README for
+    /// details.
+    #[clap(long, env = "FIELD_MAPPING_FILE")]
+    pub field_mapping_file: Option<PathBuf>,
+
+    /// Path to a MaxMind DB file with ASN data, like `GeoLite2-ASN.mmdb`. If
+    /// set, the `geo_asn` and `geo_as_org` columns are filled from it. The
+    /// file is reloaded when it changes.
+    #[clap(long, env = "GEOIP_ASN_DB")]
+    pub geoip_asn_db: Option<PathBuf>,
+
+    /// Path to a MaxMind DB file with location data, like
+    /// `GeoLite2-City.mmdb` or `GeoLite2-Country.mmdb`. If set, the
+    /// `geo_country_code`, `geo_region`, and `geo_city` columns are filled
+    /// from it. The file is reloaded when it changes.
+    #[clap(long, env = "GEOIP_CITY_DB")]
+    pub geoip_city_db: Option<PathBuf>,
+
     /// The maximum size of one INSERT batch to dump into the database. Must be
     /// at least 1
     #[clap(long, env = "INSERT_BATCH_SIZE", default_value = "10")]
     pub insert_batch_size: usize,
 
+    /// How many times a failed INSERT gets retried if the error looks like it
+    /// might go away on its own, like the database restarting. Set to 0 to
+    /// disable retries.
+    #[clap(long, env = "INSERT_RETRY_ATTEMPTS", default_value = "5")]
+    pub insert_retry_attempts: u32,
+
+    /// The time in milliseconds to wait before the first retry of a failed
+    /// INSERT. This doubles with every further attempt.
+    #[clap(long, env = "INSERT_RETRY_BACKOFF", default_value = "100")]
+    pub insert_retry_backoff: u64,
+
+    /// The maximum time in milliseconds spent on retrying a failed INSERT.
+    /// After that, the batch gets dropped, even if there are attempts left.
+    #[clap(long, env = "INSERT_RETRY_DEADLINE", default_value = "10000")]
+    pub insert_retry_deadline: u64,
+
     /// To reduce database load, we wait at least this amount of milliseconds
     /// before firing a batched insert query to give the buffer the time to
     /// reach INSERT_BATCH_SIZE. If the buffer is full, however, we ignore this
@@ -52,11 +187,29 @@ pub struct Settings {
     #[clap(long, env = "INSERT_TIMEOUT", default_value = "1000")]
     pub insert_timeout: u64,
 
+    /// Defines if and how client addresses in `access_log` and `error_log`
+    /// get anonymized before they're stored. Check the README for details.
+    #[clap(value_enum, long, env = "IP_ANONYMIZATION", default_value_t = IpAnonymization::None)]
+    pub ip_anonymization: IpAnonymization,
+
+    /// The secret used to derive pseudonyms with IP_ANONYMIZATION=hmac,
+    /// together with a random daily salt that's only kept in memory.
+    #[clap(long, env = "IP_ANONYMIZATION_KEY", hide_env_values = true)]
+    pub ip_anonymization_key: Option<String>,
+
     /// Where the server should listen on. This can be either a UDP socket
-    /// address (`127.0.0.1:8514`), or a unix domain socket path prefixed with
-    /// `unix:` (`unix:/var/run/ngxslpg.sock`).
-    #[clap(long, env = "LISTEN_ADDR", default_value = "[::1]:8514")]
-    pub listen_addr: String,
+    /// address (`127.0.0.1:8514`), a TCP socket address prefixed with `tcp:`
+    /// (`tcp:127.0.0.1:8514`), a TCP socket address for TLS prefixed with
+    /// `tls:` (`tls:127.0.0.1:6514`), or a unix domain socket path prefixed
+    /// with `unix:` (`unix:/var/run/ngxslpg.sock`). To listen on multiple
+    /// addresses, separate them with commas.
+    #[clap(
+        long,
+        env = "LISTEN_ADDR",
+        default_value = "[::1]:8514",
+        value_delimiter = ','
+    )]
+    pub listen_addr: Vec<ListenAddr>,
 
     /// Defines how the log output will be formatted
     #[clap(value_enum, long, env = "LOG_FORMAT", default_value_t = LogFormat::TextColor)]
@@ -66,11 +219,203 @@ pub struct Settings {
     #[clap(value_enum, long, env = "LOG_LEVEL", default_value_t = LogLevel::Warn)]
     pub log_level: LogLevel,
 
+    /// The maximum size of a single syslog message received over TCP, in
+    /// bytes. Connections that send larger messages get closed.
+    #[clap(long, env = "MAX_MESSAGE_SIZE", default_value = "1048576")]
+    pub max_message_size: usize,
+
+    /// If set, an HTTP listener on this socket address (`[::1]:9514`) serves
+    /// Prometheus metrics about received, dropped, and inserted log lines.
+    #[clap(long, env = "METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// A comma-separated list of your own domains, like
+    /// `example.com,example.org`. Referers from these domains, and their
+    /// subdomains, are marked as internal, in addition to referers from the
+    /// requested host.
+    #[clap(long, env = "OWN_DOMAINS", value_delimiter = ',')]
+    pub own_domains: Vec<String>,
+
+    /// If set, user agents get classified into browser, OS, and device, which
+    /// are stored in the `ua_*` columns of `access_log`. Check the README for
+    /// details.
+    #[clap(long, env = "PARSE_USER_AGENTS")]
+    pub parse_user_agents: bool,
+
+    /// Defines if `access_log` entries with missing fields get rejected, or
+    /// stored with NULL in their place. Missing timestamps are replaced with
+    /// the time the entry was received.
+    #[clap(value_enum, long, env = "PARSING_MODE", default_value_t = ParsingMode::Strict)]
+    pub parsing_mode: ParsingMode,
+
     /// Maximum number of messages in the processing queue
     #[clap(long, env = "QUEUE_SIZE", default_value = "50")]
     pub queue_size: usize,
 
+    /// If set, each source address can send this many messages per second on
+    /// average. Everything above that gets dropped. Has to be at least 1.
+    #[clap(long, env = "RATE_LIMIT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+
+    /// How many messages a source address can send in a short burst before
+    /// RATE_LIMIT kicks in. Defaults to RATE_LIMIT.
+    #[clap(long, env = "RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// Same as nginx' `real_ip_recursive on`. If set, the real client address
+    /// is the last address in `X-Forwarded-For` that isn't in TRUSTED_PROXIES,
+    /// instead of just the last address.
+    #[clap(long, env = "REAL_IP_RECURSIVE")]
+    pub real_ip_recursive: bool,
+
+    /// A comma-separated list of query parameter names, like `token,email`.
+    /// Their values in `req_uri`, `req_query`, and `client_referer`, and in
+    /// `error_log`'s `request`, `subrequest`, and `referrer`, get replaced with `[REDACTED]` before
+    /// they're stored. Names are case-insensitive.
+    #[clap(long, env = "REDACT_QUERY_PARAMS", value_delimiter = ',')]
+    pub redact_query_params: Vec<String>,
+
+    /// Number of days after which rows in the `rejected_datagram` table get
+    /// deleted. Only used if STORE_REJECTED is set.
+    #[clap(long, env = "REJECTED_RETENTION_DAYS", default_value = "7")]
+    pub rejected_retention_days: u16,
+
+    /// Path to a TOML file with rules that decide which `access_log` entries
+    /// get stored, and which share of them. Check the README for details.
+    #[clap(long, env = "SAMPLING_RULES_FILE")]
+    pub sampling_rules_file: Option<PathBuf>,
+
+    /// The time in milliseconds the bridge waits for pending log lines to be
+    /// stored after receiving SIGTERM or SIGINT. If that takes longer, the
+    /// remaining log lines are dropped, and the bridge exits with an error.
+    /// Inserts aren't retried then, and batches that can't be inserted within
+    /// a quarter of this time are spooled, if SPOOL_DIR is set.
+    #[clap(long, env = "SHUTDOWN_TIMEOUT", default_value = "10000")]
+    pub shutdown_timeout: u64,
+
+    /// If set, batches that can't be inserted, even after all retries, get
+    /// written into this directory instead of being dropped. They are replayed
+    /// once the database is available again, also after a restart.
+    #[clap(long, env = "SPOOL_DIR")]
+    pub spool_dir: Option<PathBuf>,
+
+    /// The maximum size of SPOOL_DIR in MiB. If the spool grows larger than
+    /// this, the oldest spooled batches get deleted.
+    #[clap(long, env = "SPOOL_MAX_SIZE", default_value = "1024")]
+    pub spool_max_size: u64,
+
+    /// Datagrams that can't be parsed get dropped. If this is set, they get
+    /// stored in the `rejected_datagram` table instead, together with the
+    /// reason they were rejected.
+    #[clap(long, env = "STORE_REJECTED")]
+    pub store_rejected: bool,
+
     /// Limits the number of threads used - defaults to the number of CPU cores
     #[clap(long, env = "THREADS")]
     pub threads: Option<usize>,
+
+    /// Path to a PEM file with the certificate chain for `tls:` listeners.
+    /// Send SIGHUP to reload it, together with TLS_KEY and TLS_CLIENT_CA.
+    #[clap(long, env = "TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to a PEM file with CA certificates. If set, `tls:` listeners only
+    /// accept clients with a certificate signed by one of these CAs.
+    #[clap(long, env = "TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Path to a PEM file with the private key for TLS_CERT.
+    #[clap(long, env = "TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// A comma-separated list of networks in CIDR notation, like
+    /// `10.0.0.0/8,2001:db8::/32`, that are trusted to send a correct
+    /// `X-Forwarded-For` header. This works like nginx' `set_real_ip_from`,
+    /// and is used to derive the `client_real_addr` column.
+    #[clap(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// How many parsed user agents are kept in memory, so they don't have to
+    /// be parsed again. Only used if PARSE_USER_AGENTS is set.
+    #[clap(long, env = "UA_CACHE_SIZE", default_value = "10000")]
+    pub ua_cache_size: usize,
+
+    /// Path to a `regexes.yaml` from uap-core, which replaces the bundled
+    /// regexes used with PARSE_USER_AGENTS.
+    #[clap(long, env = "UA_REGEXES_FILE")]
+    pub ua_regexes_file: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use clap::Parser;
+
+    #[test]
+    fn parses_listen_addr_prefixes() {
+        assert_eq!(
+            Ok(ListenAddr::Udp("127.0.0.1:8514".to_owned())),
+            "127.0.0.1:8514".parse()
+        );
+        assert_eq!(
+            Ok(ListenAddr::Tcp("[::1]:8514".to_owned())),
+            "tcp:[::1]:8514".parse()
+        );
+        assert_eq!(
+            Ok(ListenAddr::Tls("[::1]:6514".to_owned())),
+            "tls:[::1]:6514".parse()
+        );
+        assert_eq!(
+            Ok(ListenAddr::Unix("/run/ngxslpg.sock".into())),
+            "unix:/run/ngxslpg.sock".parse()
+        );
+        assert_eq!(
+            Ok(ListenAddr::Udp("localhost:8514".to_owned())),
+            "localhost:8514".parse()
+        );
+        assert!("".parse::<ListenAddr>().is_err());
+        assert!("8514".parse::<ListenAddr>().is_err());
+        assert!("127.0.0.1".parse::<ListenAddr>().is_err());
+        assert!("tcp:127.0.0.1:99999".parse::<ListenAddr>().is_err());
+        assert!("tls:".parse::<ListenAddr>().is_err());
+        assert!("udp:127.0.0.1:8514".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn parses_list_of_listen_addrs() {
+        let settings = Settings::try_parse_from([
+            "ngxslpg",
+            "--database-url",
+            "postgres://localhost/nginx_logs",
+            "--listen-addr",
+            "0.0.0.0:8514,[::]:8514, unix:/run/ngxslpg.sock",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                ListenAddr::Udp("0.0.0.0:8514".to_owned()),
+                ListenAddr::Udp("[::]:8514".to_owned()),
+                ListenAddr::Unix("/run/ngxslpg.sock".into()),
+            ],
+            settings.listen_addr
+        );
+    }
+
+    #[test]
+    fn rejects_rate_limit_of_zero() {
+        let parse = |rate_limit| {
+            Settings::try_parse_from([
+                "ngxslpg",
+                "--database-url",
+                "postgres://localhost/nginx_logs",
+                "--rate-limit",
+                rate_limit,
+            ])
+        };
+
+        assert_eq!(Some(1), parse("1").unwrap().rate_limit);
+        assert!(parse("0").is_err());
+    }
 }
@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use metrics::counter;
+use tracing::warn;
+
+use crate::{instrumentation, settings::Settings};
+
+/// Buckets that are full again are no different from new ones, so they get
+/// removed every once in a while to keep the map from growing forever.
+const BUCKET_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Dropped messages are logged at most once per this interval and reason,
+/// so a misbehaving sender can't flood the log.
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Decides which senders are allowed to send messages, and how many. This is
+/// shared between all receivers, so a sender can't get around the rate limit
+/// by using multiple sockets.
+pub struct SourceFilter {
+    allowed_sources: Vec<IpNet>,
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    not_allowed_warning: ThrottledWarning,
+    rate_limited_warning: ThrottledWarning,
+}
+
+impl SourceFilter {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let rate_limiter = settings.rate_limit.map(|per_second| {
+            let burst = settings.rate_limit_burst.unwrap_or(per_second);
+            Mutex::new(RateLimiter::new(RateLimit {
+                per_second: f64::from(per_second),
+                burst: f64::from(burst.max(1)),
+            }))
+        });
+
+        Self {
+            allowed_sources: settings.allowed_sources.clone(),
+            rate_limiter,
+            not_allowed_warning: ThrottledWarning::new("source is not in ALLOWED_SOURCES"),
+            rate_limited_warning: ThrottledWarning::new("source exceeded RATE_LIMIT"),
+        }
+    }
+
+    /// Checks if a connection from that address should be accepted at all.
+    /// Messages from it have to pass [Self::accepts_message] as well.
+    pub fn accepts_connection(&self, ip: IpAddr) -> bool {
+        if self.is_allowed(ip) {
+            return true;
+        }
+
+        counter!(instrumentation::SOURCE_DROPS, "reason" => "not_allowed").increment(1);
+        self.not_allowed_warning.record(ip);
+        false
+    }
+
+    /// Checks if a message from that address should be processed. This uses
+    /// up one token from the sender's bucket, if there's a rate limit.
+    pub fn accepts_message(&self, ip: IpAddr) -> bool {
+        if !self.accepts_connection(ip) {
+            return false;
+        }
+
+        let Some(rate_limiter) = &self.rate_limiter else {
+            return true;
+        };
+        let allowed = rate_limiter
+            .lock()
+            .expect("rate limiter lock to not be poisoned")
+            .allows(ip, Instant::now());
+        if !allowed {
+            counter!(instrumentation::SOURCE_DROPS, "reason" => "rate_limited").increment(1);
+            self.rate_limited_warning.record(ip);
+        }
+
+        allowed
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 senders on a dual-stack socket show up as mapped IPv6 addresses,
+        // but should still match IPv4 networks.
+        let ip = ip.to_canonical();
+        self.allowed_sources.is_empty() || self.allowed_sources.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+/// A classic token bucket per sender. Every message takes one token, and
+/// tokens refill at `per_second`, up to `burst`.
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    cleaned_up_at: Instant,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+            cleaned_up_at: Instant::now(),
+        }
+    }
+
+    fn allows(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let limit = self.limit;
+        if now.saturating_duration_since(self.cleaned_up_at) >= BUCKET_CLEANUP_INTERVAL {
+            self.buckets
+                .retain(|_, bucket| bucket.tokens_at(&limit, now) < limit.burst);
+            self.cleaned_up_at = now;
+        }
+
+        self.buckets
+            .entry(ip)
+            .or_insert(TokenBucket {
+                tokens: limit.burst,
+                updated_at: now,
+            })
+            .take(&limit, now)
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn tokens_at(&self, limit: &RateLimit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        (self.tokens + elapsed * limit.per_second).min(limit.burst)
+    }
+
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.tokens = self.tokens_at(limit, now);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Logs a warning about dropped messages, but at most once per
+/// [WARNING_INTERVAL]. Drops in between are only counted, and the count is
+/// part of the next warning.
+struct ThrottledWarning {
+    reason: &'static str,
+    state: Mutex<(Option<Instant>, u64)>,
+}
+
+impl ThrottledWarning {
+    fn new(reason: &'static str) -> Self {
+        Self {
+            reason,
+            state: Mutex::new((None, 0)),
+        }
+    }
+
+    fn record(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("warning lock to not be poisoned");
+        let (warned_at, dropped) = &mut *state;
+        *dropped += 1;
+
+        if warned_at.is_none_or(|at| now.duration_since(at) >= WARNING_INTERVAL) {
+            warn!(
+                "Dropped message from {}: {} ({} dropped for this reason since the last warning)",
+                ip, self.reason, dropped
+            );
+            *warned_at = Some(now);
+            *dropped = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filter(allowed_sources: &[&str], rate_limit: Option<(u32, u32)>) -> SourceFilter {
+        SourceFilter {
+            allowed_sources: allowed_sources.iter().map(|s| s.parse().unwrap()).collect(),
+            rate_limiter: rate_limit.map(|(per_second, burst)| {
+                Mutex::new(RateLimiter::new(RateLimit {
+                    per_second: f64::from(per_second),
+                    burst: f64::from(burst),
+                }))
+            }),
+            not_allowed_warning: ThrottledWarning::new("not allowed"),
+            rate_limited_warning: ThrottledWarning::new("rate limited"),
+        }
+    }
+
+    #[test]
+    fn allows_everything_without_allowed_sources() {
+        let filter = filter(&[], None);
+        assert!(filter.accepts_message("192.0.2.1".parse().unwrap()));
+        assert!(filter.accepts_message("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn only_allows_allowed_sources() {
+        let filter = filter(&["10.0.0.0/8", "2001:db8::/32"], None);
+        assert!(filter.accepts_message("10.1.2.3".parse().unwrap()));
+        assert!(filter.accepts_message("2001:db8::1".parse().unwrap()));
+        assert!(filter.accepts_message("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!filter.accepts_message("192.0.2.1".parse().unwrap()));
+        assert!(!filter.accepts_connection("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn limits_each_source_separately() {
+        let filter = filter(&[], Some((1, 2)));
+        let a = "192.0.2.1".parse().unwrap();
+        let b = "192.0.2.2".parse().unwrap();
+
+        assert!(filter.accepts_message(a));
+        assert!(filter.accepts_message(a));
+        assert!(!filter.accepts_message(a));
+        assert!(filter.accepts_message(b));
+    }
+
+    #[test]
+    fn refills_tokens_over_time() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            per_second: 10.0,
+            burst: 1.0,
+        });
+        let ip = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.allows(ip, now));
+        assert!(!limiter.allows(ip, now));
+        assert!(!limiter.allows(ip, now + Duration::from_millis(50)));
+        assert!(limiter.allows(ip, now + Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn cleans_up_full_buckets() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            per_second: 1.0,
+            burst: 5.0,
+        });
+        let now = Instant::now();
+        limiter.allows("192.0.2.1".parse().unwrap(), now);
+
+        limiter.allows("192.0.2.2".parse().unwrap(), now + BUCKET_CLEANUP_INTERVAL);
+        assert_eq!(1, limiter.buckets.len());
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::warn;
+
+use crate::datagram::Datagram;
+
+/// Segments are rotated once they're larger than this. Every segment gets
+/// replayed in one transaction, so this shouldn't be too large.
+const SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "jsonl";
+const FAILED_SEGMENT_EXTENSION: &str = "failed";
+
+/// If the database is unavailable for longer than the insert retries take,
+/// batches get written into this on-disk spool instead of being dropped, and
+/// get replayed once the database is back.
+///
+/// The spool is a directory of segment files, each containing one JSON
+/// document per line. Segment names are increasing numbers, so sorting them
+/// by name sorts them by age. Segments are only ever written to by the one
+/// [Spool] instance, so nothing in here needs any locking.
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+    total_size: u64,
+    last_segment_id: u128,
+    active_segment: Option<(PathBuf, fs::File, u64)>,
+}
+
+impl Spool {
+    /// Opens the spool at `dir`, creating it if needed. Segments that are
+    /// left over from a previous run are picked up.
+    pub async fn open(dir: PathBuf, max_size: u64) -> Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        let mut spool = Self {
+            dir,
+            max_size,
+            total_size: 0,
+            last_segment_id: 0,
+            active_segment: None,
+        };
+        for segment in spool.segments().await? {
+            spool.total_size += fs::metadata(&segment).await?.len();
+        }
+
+        Ok(spool)
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    /// Appends all datagrams to the active segment, and deletes the oldest
+    /// segments if that pushes the spool over its maximum size.
+    pub async fn append(&mut self, datagrams: &[Datagram]) -> Result<usize> {
+        let mut lines = String::new();
+        for datagram in datagrams {
+            lines.push_str(&serde_json::to_string(&SpooledDatagram::from(datagram))?);
+            lines.push('\n');
+        }
+
+        if !matches!(&self.active_segment, Some((_, _, size)) if *size < SEGMENT_SIZE) {
+            let path = self.next_segment_path();
+            // Payloads can contain AUTH_KEYS, so only the bridge's own user
+            // gets to read them.
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path)
+                .await?;
+            self.active_segment = Some((path, file, 0));
+        }
+
+        let (_, file, size) = self
+            .active_segment
+            .as_mut()
+            .expect("active segment was just created");
+        file.write_all(lines.as_bytes()).await?;
+        file.flush().await?;
+        // The spool is there for when things go wrong, so batches have to
+        // survive a crash of the whole host as well.
+        file.sync_data().await?;
+        *size += lines.len() as u64;
+        self.total_size += lines.len() as u64;
+
+        self.evict().await
+    }
+
+    /// Returns the path of the oldest segment, if there is one. This can be
+    /// the segment that's currently being written to, which only gets closed
+    /// once it's removed.
+    pub async fn oldest_segment(&self) -> Result<Option<PathBuf>> {
+        Ok(self.segments().await?.into_iter().next())
+    }
+
+    /// Reads all datagrams from a segment. Lines that can't be read, like a
+    /// partially written line after a crash, are skipped.
+    pub async fn read_segment(path: &Path) -> Result<Vec<Datagram>> {
+        let content = fs::read_to_string(path).await?;
+        let datagrams = content
+            .lines()
+            .filter_map(|line| match serde_json::from_str::<SpooledDatagram>(line) {
+                Ok(spooled) => Some(spooled.into()),
+                Err(err) => {
+                    warn!("Skipping invalid line in {}: {}", path.display(), err);
+                    None
+                }
+            })
+            .collect();
+
+        Ok(datagrams)
+    }
+
+    /// Deletes a segment after it has been replayed.
+    pub async fn remove_segment(&mut self, path: &Path) -> Result<()> {
+        self.close_if_active(path);
+        let size = fs::metadata(path).await?.len();
+        fs::remove_file(path).await?;
+        self.total_size = self.total_size.saturating_sub(size);
+
+        Ok(())
+    }
+
+    /// Renames a segment that could not be replayed for reasons that won't go
+    /// away on their own, so it doesn't get replayed over and over again, but
+    /// is still around for manual inspection.
+    pub async fn mark_segment_failed(&mut self, path: &Path) -> Result<()> {
+        self.close_if_active(path);
+        let size = fs::metadata(path).await?.len();
+        fs::rename(path, path.with_extension(FAILED_SEGMENT_EXTENSION)).await?;
+        self.total_size = self.total_size.saturating_sub(size);
+
+        Ok(())
+    }
+
+    /// Makes sure the next batch gets written into a new segment, if `path`
+    /// is the segment that's currently being written to.
+    fn close_if_active(&mut self, path: &Path) {
+        if matches!(&self.active_segment, Some((active, _, _)) if active == path) {
+            self.active_segment = None;
+        }
+    }
+
+    /// Deletes the oldest segments until the spool fits into its maximum size
+    /// again. The segment that's currently being written to is never deleted,
+    /// since it contains the batch that was just spooled. Returns the number
+    /// of deleted segments.
+    async fn evict(&mut self) -> Result<usize> {
+        let mut evicted = 0;
+        let active = self
+            .active_segment
+            .as_ref()
+            .map(|(path, _, _)| path.clone());
+        for segment in self.segments().await? {
+            if self.total_size <= self.max_size {
+                break;
+            }
+            if Some(&segment) == active.as_ref() {
+                continue;
+            }
+
+            warn!(
+                "Spool is larger than its maximum size, deleting {}",
+                segment.display()
+            );
+            self.remove_segment(&segment).await?;
+            evicted += 1;
+        }
+
+        Ok(evicted)
+    }
+
+    /// All segments, oldest first.
+    async fn segments(&self) -> Result<Vec<PathBuf>> {
+        let mut segments = vec![];
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+                segments.push(path);
+            }
+        }
+
+        segments.sort();
+        Ok(segments)
+    }
+
+    fn next_segment_path(&mut self) -> PathBuf {
+        // Timestamps make sure segments from previous runs are always older.
+        // The counter makes sure that names are unique even if the clock
+        // doesn't move between two segments.
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default() as u128;
+        self.last_segment_id = now.max(self.last_segment_id + 1);
+
+        self.dir.join(format!(
+            "{:024}.{}",
+            self.last_segment_id, SEGMENT_EXTENSION
+        ))
+    }
+}
+
+/// The on-disk representation of a [Datagram]. Payloads that aren't valid
+/// UTF-8 are stored as raw bytes, everything else as a string.
+#[derive(Deserialize, Serialize)]
+struct SpooledDatagram {
+    received_at: DateTime<Utc>,
+    source_addr: Option<SocketAddr>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    invalid_payload: Option<Vec<u8>>,
+}
+
+impl From<&Datagram> for SpooledDatagram {
+    fn from(value: &Datagram) -> Self {
+        let (payload, invalid_payload) = match &value.payload {
+            Ok(payload) => (Some(payload.clone()), None),
+            Err(err) => (None, Some(err.as_bytes().to_vec())),
+        };
+
+        Self {
+            received_at: value.received_at,
+            source_addr: value.source_addr,
+            payload,
+            invalid_payload,
+        }
+    }
+}
+
+impl From<SpooledDatagram> for Datagram {
+    fn from(value: SpooledDatagram) -> Self {
+        let payload = match (value.payload, value.invalid_payload) {
+            (Some(payload), _) => Ok(payload),
+            (None, bytes) => String::from_utf8(bytes.unwrap_or_default()),
+        };
+
+        Self {
+            received_at: value.received_at,
+            source_addr: value.source_addr,
+            payload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("ngxslpg-spool-{}", Uuid::new_v4()))
+    }
+
+    fn datagram(payload: &str) -> Datagram {
+        Datagram {
+            received_at: Utc::now(),
+            source_addr: Some("127.0.0.1:1234".parse().unwrap()),
+            payload: Ok(payload.to_owned()),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_appended_datagrams() {
+        let dir = temp_dir();
+        let mut spool = Spool::open(dir.clone(), u64::MAX).await.unwrap();
+        let invalid = Datagram {
+            payload: String::from_utf8(vec![0xff, 0x00]),
+            ..datagram("")
+        };
+        spool.append(&[datagram("hello"), invalid]).await.unwrap();
+
+        let segment = spool.oldest_segment().await.unwrap().unwrap();
+        let datagrams = Spool::read_segment(&segment).await.unwrap();
+        assert_eq!(2, datagrams.len());
+        assert_eq!("hello", datagrams[0].payload.as_ref().unwrap());
+        assert_eq!(
+            &[0xff, 0x00],
+            datagrams[1].payload.as_ref().unwrap_err().as_bytes()
+        );
+
+        spool.remove_segment(&segment).await.unwrap();
+        assert!(spool.oldest_segment().await.unwrap().is_none());
+        assert_eq!(0, spool.total_size());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_writing_to_active_segment_until_it_gets_removed() {
+        let dir = temp_dir();
+        let mut spool = Spool::open(dir.clone(), u64::MAX).await.unwrap();
+        spool.append(&[datagram("first")]).await.unwrap();
+
+        // Looking at the oldest segment, like a replay during an outage
+        // does, doesn't start a new segment.
+        let first = spool.oldest_segment().await.unwrap().unwrap();
+        spool.append(&[datagram("second")]).await.unwrap();
+        assert_eq!(first, spool.oldest_segment().await.unwrap().unwrap());
+        assert_eq!(2, Spool::read_segment(&first).await.unwrap().len());
+
+        spool.remove_segment(&first).await.unwrap();
+        spool.append(&[datagram("third")]).await.unwrap();
+
+        let second = spool.oldest_segment().await.unwrap().unwrap();
+        assert_ne!(first, second);
+        let datagrams = Spool::read_segment(&second).await.unwrap();
+        assert_eq!(1, datagrams.len());
+        assert_eq!("third", datagrams[0].payload.as_ref().unwrap());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicts_oldest_segments_when_full() {
+        let dir = temp_dir();
+        let mut spool = Spool::open(dir.clone(), u64::MAX).await.unwrap();
+        spool.append(&[datagram("first")]).await.unwrap();
+        // Pretend the first segment got full.
+        spool.active_segment = None;
+        spool.max_size = spool.total_size() + spool.total_size() / 2;
+        let evicted = spool.append(&[datagram("second")]).await.unwrap();
+
+        assert_eq!(1, evicted);
+        let segment = spool.oldest_segment().await.unwrap().unwrap();
+        let datagrams = Spool::read_segment(&segment).await.unwrap();
+        assert_eq!("second", datagrams[0].payload.as_ref().unwrap());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_evicts_active_segment() {
+        let dir = temp_dir();
+        let mut spool = Spool::open(dir.clone(), 1).await.unwrap();
+        let evicted = spool.append(&[datagram("hello")]).await.unwrap();
+
+        assert_eq!(0, evicted);
+        let segment = spool.oldest_segment().await.unwrap().unwrap();
+        assert_eq!(1, Spool::read_segment(&segment).await.unwrap().len());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn picks_up_segments_after_reopening() {
+        let dir = temp_dir();
+        let mut spool = Spool::open(dir.clone(), u64::MAX).await.unwrap();
+        spool.append(&[datagram("hello")]).await.unwrap();
+        let size = spool.total_size();
+        drop(spool);
+
+        let spool = Spool::open(dir.clone(), u64::MAX).await.unwrap();
+        assert_eq!(size, spool.total_size());
+        let segment = spool.oldest_segment().await.unwrap().unwrap();
+        assert_eq!(1, Spool::read_segment(&segment).await.unwrap().len());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{Result, bail};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// The longest message length that could be sent with octet-counting that
+/// we're willing to read the digits of. Anything longer is above any sane
+/// maximum message size anyway.
+const MAX_LENGTH_DIGITS: usize = 10;
+
+/// Reads the next syslog message from a stream, as described in RFC6587.
+/// Senders can either use octet-counting (`<length> <message>`), or separate
+/// messages with a LF. Relays like rsyslog decide that per message, so this
+/// does as well: syslog messages always start with a `<`, so if there's a
+/// digit instead, it's a length.
+///
+/// Returns [None] if the stream was closed. Errors mean the stream can't be
+/// trusted to be in sync anymore, so the connection should be closed.
+pub async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    loop {
+        let Some(&first) = reader.fill_buf().await?.first() else {
+            return Ok(None);
+        };
+
+        let frame = if first.is_ascii_digit() {
+            read_octet_counted(reader, max_size).await?
+        } else {
+            read_line(reader, max_size).await?
+        };
+
+        // Empty lines can show up between messages, like with CRLF line
+        // endings or a trailing LF after octet-counted frames.
+        if !frame.is_empty() {
+            return Ok(Some(frame));
+        }
+    }
+}
+
+async fn read_octet_counted<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Vec<u8>> {
+    let mut digits = vec![];
+    (&mut *reader)
+        .take(MAX_LENGTH_DIGITS as u64 + 1)
+        .read_until(b' ', &mut digits)
+        .await?;
+    if digits.pop() != Some(b' ') {
+        bail!("octet-counted frame without a valid length");
+    }
+
+    let length: usize = std::str::from_utf8(&digits)?.parse()?;
+    if length > max_size {
+        bail!(
+            "message is {} bytes long, but only {} are allowed",
+            length,
+            max_size
+        );
+    }
+
+    // The buffer only grows as data actually arrives, so a peer can't make
+    // us allocate a lot of memory just by claiming a large length.
+    let mut frame = vec![];
+    (&mut *reader)
+        .take(length as u64)
+        .read_to_end(&mut frame)
+        .await?;
+    if frame.len() < length {
+        bail!(
+            "stream ended after {} of {} bytes of an octet-counted frame",
+            frame.len(),
+            length
+        );
+    }
+
+    Ok(frame)
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, max_size: usize) -> Result<Vec<u8>> {
+    let mut frame = vec![];
+    (&mut *reader)
+        .take(max_size as u64 + 1)
+        .read_until(b'\n', &mut frame)
+        .await?;
+
+    if frame.last() == Some(&b'\n') {
+        frame.pop();
+        if frame.last() == Some(&b'\r') {
+            frame.pop();
+        }
+    } else if frame.len() > max_size {
+        bail!("message is longer than {} bytes", max_size);
+    }
+
+    // If the stream ended without a final LF, the frame is still complete.
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read_all(mut stream: &[u8], max_size: usize) -> Result<Vec<Vec<u8>>> {
+        let mut frames = vec![];
+        while let Some(frame) = read_frame(&mut stream, max_size).await? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[tokio::test]
+    async fn reads_octet_counted_frames() {
+        let frames = read_all(b"5 <1>ab7 <2>\ncde", 1024).await.unwrap();
+        assert_eq!(vec![b"<1>ab".to_vec(), b"<2>\ncde".to_vec()], frames);
+    }
+
+    #[tokio::test]
+    async fn reads_lf_delimited_frames() {
+        let frames = read_all(b"<1>ab\r\n\n<2>cd\n<3>ef", 1024).await.unwrap();
+        assert_eq!(
+            vec![b"<1>ab".to_vec(), b"<2>cd".to_vec(), b"<3>ef".to_vec()],
+            frames
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_mixed_framing() {
+        let frames = read_all(b"5 <1>ab\n<2>cd\n", 1024).await.unwrap();
+        assert_eq!(vec![b"<1>ab".to_vec(), b"<2>cd".to_vec()], frames);
+    }
+
+    #[tokio::test]
+    async fn is_err_for_oversized_frames() {
+        assert!(read_all(b"11 <1>abcdefgh", 10).await.is_err());
+        assert!(read_all(b"<1>abcdefgh\n", 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn is_err_for_frames_shorter_than_claimed_length() {
+        let mut stream: &[u8] = b"1000000 <1>ab";
+        let err = read_frame(&mut stream, usize::MAX).await.unwrap_err();
+        assert!(err.to_string().contains("after 5 of 1000000 bytes"));
+    }
+
+    #[tokio::test]
+    async fn is_err_for_truncated_octet_counted_frame() {
+        assert!(read_all(b"10 <1>ab", 1024).await.is_err());
+        assert!(read_all(b"12345678901234 <1>ab", 1024).await.is_err());
+    }
+}
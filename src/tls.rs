@@ -0,0 +1,193 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Context, Result, bail};
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::settings::Settings;
+
+/// Paths to all PEM files needed to run a TLS listener.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+
+    /// If set, clients have to present a certificate signed by one of the
+    /// CAs in this file.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let (Some(cert), Some(key)) = (&settings.tls_cert, &settings.tls_key) else {
+            bail!("TLS_CERT and TLS_KEY are required for `tls:` listeners");
+        };
+
+        Ok(Self {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: settings.tls_client_ca.clone(),
+        })
+    }
+}
+
+/// A TLS config that can be swapped out at runtime, so renewed certificates
+/// can be used without restarting the bridge. Connections that are already
+/// established keep using the config they started with.
+pub struct ReloadableTlsConfig {
+    files: TlsFiles,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl ReloadableTlsConfig {
+    pub fn load(files: TlsFiles) -> Result<Self> {
+        let config = load_server_config(&files)?;
+        Ok(Self {
+            files,
+            config: RwLock::new(Arc::new(config)),
+        })
+    }
+
+    /// Loads all files again. If that fails, the previous config stays in
+    /// use.
+    pub fn reload(&self) -> Result<()> {
+        let config = load_server_config(&self.files)?;
+        *self
+            .config
+            .write()
+            .expect("TLS config lock to not be poisoned") = Arc::new(config);
+
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        let config = self
+            .config
+            .read()
+            .expect("TLS config lock to not be poisoned");
+        TlsAcceptor::from(config.clone())
+    }
+}
+
+fn load_server_config(files: &TlsFiles) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(&files.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading {}", files.cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(&files.key)
+        .with_context(|| format!("reading {}", files.key.display()))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &files.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(client_ca)
+                .with_context(|| format!("reading {}", client_ca.display()))?
+            {
+                roots.add(cert?)?;
+            }
+
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_single_cert(certs, key)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rustls::{ClientConfig, pki_types::ServerName};
+    use tokio_rustls::TlsConnector;
+
+    /// Writes a new self-signed certificate for `localhost` into `files`, and
+    /// returns it.
+    fn write_certificate(files: &TlsFiles) -> CertificateDer<'static> {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(&files.cert, certified.cert.pem()).unwrap();
+        std::fs::write(&files.key, certified.key_pair.serialize_pem()).unwrap();
+        certified.cert.der().clone()
+    }
+
+    fn write_files() -> (TlsFiles, CertificateDer<'static>) {
+        let dir = std::env::temp_dir().join(format!("ngxslpg-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let files = TlsFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            client_ca: None,
+        };
+        let cert = write_certificate(&files);
+        (files, cert)
+    }
+
+    /// Runs a handshake against the config in memory, with a client that
+    /// only trusts `cert`.
+    async fn handshake_succeeds(
+        config: &ReloadableTlsConfig,
+        cert: CertificateDer<'static>,
+    ) -> bool {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let acceptor = config.acceptor();
+        let server = tokio::spawn(async move { acceptor.accept(server).await.is_ok() });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        // The client has to stay connected until the server is done, since
+        // the server still sends session tickets after the client is done.
+        let client = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await;
+        let accepted = server.await.unwrap();
+
+        client.is_ok() && accepted
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_files() {
+        let (files, previous_cert) = write_files();
+        let config = ReloadableTlsConfig::load(files.clone()).unwrap();
+        assert!(handshake_succeeds(&config, previous_cert.clone()).await);
+
+        let cert = write_certificate(&files);
+        assert!(!handshake_succeeds(&config, cert.clone()).await);
+
+        config.reload().unwrap();
+        assert!(handshake_succeeds(&config, cert).await);
+        assert!(!handshake_succeeds(&config, previous_cert).await);
+
+        std::fs::remove_dir_all(files.cert.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_previous_config_if_reload_fails() {
+        let (files, cert) = write_files();
+        let config = ReloadableTlsConfig::load(files.clone()).unwrap();
+
+        std::fs::write(&files.key, "meow").unwrap();
+        assert!(config.reload().is_err());
+        assert!(handshake_succeeds(&config, cert).await);
+
+        std::fs::remove_dir_all(files.cert.parent().unwrap()).unwrap();
+    }
+}
@@ -1,30 +1,126 @@
 use sqlx::{PgPool, postgres::PgConnectOptions};
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::{io::AsyncWriteExt, time::sleep};
 
 use nginx_syslog_postgres_bridge::{
-    Bridge,
-    settings::{LogFormat, LogLevel, Settings},
+    Bridge, SyslogSocket,
+    settings::{IpAnonymization, ListenAddr, LogFormat, LogLevel, ParsingMode, Settings},
+    tls::{ReloadableTlsConfig, TlsFiles},
 };
+use rustls::{
+    ClientConfig, RootCertStore,
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName},
+};
+use std::sync::Arc;
+use tokio_rustls::TlsConnector;
 
-pub async fn spawn_test_server(db_pool: PgPool) -> String {
-    let settings = Settings {
+pub fn test_settings() -> Settings {
+    Settings {
+        allowed_sources: vec![],
+        auth_keys: vec![],
+        combined_log_tags: vec![],
         database_url: PgConnectOptions::new(),
+        field_mapping_file: None,
+        geoip_asn_db: None,
+        geoip_city_db: None,
         insert_batch_size: 1,
+        insert_retry_attempts: 0,
+        insert_retry_backoff: 100,
+        insert_retry_deadline: 1000,
         insert_timeout: 100,
-        listen_addr: "127.0.0.1:0".to_string(),
+        ip_anonymization: IpAnonymization::None,
+        ip_anonymization_key: None,
+        listen_addr: vec![ListenAddr::Udp("127.0.0.1:0".to_owned())],
         log_format: LogFormat::TextColor,
         log_level: LogLevel::Trace,
+        max_message_size: 1024 * 1024,
+        metrics_addr: None,
+        own_domains: vec![],
+        parse_user_agents: false,
+        parsing_mode: ParsingMode::Strict,
         queue_size: 100,
+        rate_limit: None,
+        rate_limit_burst: None,
+        real_ip_recursive: false,
+        redact_query_params: vec![],
+        rejected_retention_days: 7,
+        sampling_rules_file: None,
+        shutdown_timeout: 1000,
+        spool_dir: None,
+        spool_max_size: 1024,
+        store_rejected: true,
         threads: None,
-    };
+        tls_cert: None,
+        tls_client_ca: None,
+        tls_key: None,
+        trusted_proxies: vec![],
+        ua_cache_size: 10,
+        ua_regexes_file: None,
+    }
+}
+
+pub async fn spawn_test_server(db_pool: PgPool) -> String {
+    spawn_test_server_with_settings(db_pool, test_settings()).await
+}
 
-    let socket = tokio::net::UdpSocket::bind(&settings.listen_addr)
-        .await
-        .unwrap();
+pub async fn spawn_test_server_with_settings(db_pool: PgPool, settings: Settings) -> String {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
     let listening_port = socket.local_addr().unwrap().port();
 
-    tokio::spawn(Bridge::run(db_pool, settings, socket.into()));
+    tokio::spawn(Bridge::run(
+        db_pool,
+        settings,
+        vec![socket.into()],
+        std::future::pending(),
+    ));
+
+    format!("127.0.0.1:{}", listening_port)
+}
+
+pub async fn spawn_tcp_test_server(db_pool: PgPool) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listening_port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(Bridge::run(
+        db_pool,
+        test_settings(),
+        vec![listener.into()],
+        std::future::pending(),
+    ));
+
+    format!("127.0.0.1:{}", listening_port)
+}
+
+/// Writes a self-signed certificate for `localhost` into a temporary
+/// directory, and returns the paths and the certificate to trust.
+pub fn write_test_certificate() -> (TlsFiles, CertificateDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("ngxslpg-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let files = TlsFiles {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+        client_ca: None,
+    };
+    std::fs::write(&files.cert, certified.cert.pem()).unwrap();
+    std::fs::write(&files.key, certified.key_pair.serialize_pem()).unwrap();
+
+    (files, certified.cert.der().clone())
+}
+
+pub async fn spawn_tls_test_server(db_pool: PgPool, files: TlsFiles) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listening_port = listener.local_addr().unwrap().port();
+    let tls_config = Arc::new(ReloadableTlsConfig::load(files).unwrap());
+
+    tokio::spawn(Bridge::run(
+        db_pool,
+        test_settings(),
+        vec![SyslogSocket::Tls(listener, tls_config)],
+        std::future::pending(),
+    ));
 
     format!("127.0.0.1:{}", listening_port)
 }
@@ -35,6 +131,34 @@ pub async fn send_datagram(bytes: &[u8], destination: String) {
     socket.send(bytes).await.unwrap();
 }
 
+pub async fn send_stream(bytes: &[u8], destination: String) {
+    let mut stream = tokio::net::TcpStream::connect(destination).await.unwrap();
+    stream.write_all(bytes).await.unwrap();
+    stream.shutdown().await.unwrap();
+}
+
+pub async fn send_tls_stream(
+    bytes: &[u8],
+    destination: String,
+    server_cert: CertificateDer<'static>,
+) -> std::io::Result<()> {
+    let mut roots = RootCertStore::empty();
+    roots.add(server_cert).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let stream = tokio::net::TcpStream::connect(destination).await?;
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await?;
+    stream.write_all(bytes).await?;
+    stream.shutdown().await
+}
+
 pub async fn wait_for_insert() {
     // [ToDo] Sooooo... this is kinda bad. However, since I store the data
     // asynchronously, I'd need to some wait to actually figure out when the
@@ -1,4 +1,11 @@
+use std::net::IpAddr;
+
+use nginx_syslog_postgres_bridge::{
+    Bridge, instrumentation,
+    settings::{IpAnonymization, ParsingMode},
+};
 use sqlx::PgPool;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 mod helpers;
 use crate::helpers::*;
@@ -6,6 +13,8 @@ use crate::helpers::*;
 const VALID_DATAGRAM_STATIC: &str = r#"<190>Aug 16 18:35:53 nginx: {"hostname":"a970744801bb","ts":"1660674953.230","server":{"name":"_","port":"80"},"client":{"addr":"172.19.0.1","forwarded_for":"","referer":"","ua":"Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:105.0) Gecko/20100101 Firefox/105.0"},"req":{"host":"localhost","length":"1703","method":"GET","proto":"HTTP/1.1","scheme":"http","uri":"/static_file_example"},"res":{"body_length":"0","duration":"0.000","length":"180","status":"304"},"upstream":{"addr":"","bytes_received":"","bytes_sent":"","cache_status":"","connect_time":"","host":"","response_length":"","response_time":"","status":""}}"#;
 const VALID_DATAGRAM_UPSTREAM: &str = r#"<190>Aug 16 18:36:32 nginx: {"hostname":"a970744801bb","ts":"1660674992.468","server":{"name":"_","port":"80"},"client":{"addr":"172.19.0.1","forwarded_for":"","referer":"","ua":"Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:105.0) Gecko/20100101 Firefox/105.0"},"req":{"host":"localhost","length":"1658","method":"GET","proto":"HTTP/1.1","scheme":"http","uri":"/upstream_proxy_example"},"res":{"body_length":"648","duration":"0.254","length":"1044","status":"200"},"upstream":{"addr":"93.184.216.34:80","bytes_received":"1041","bytes_sent":"1705","cache_status":"","connect_time":"0.128","host":"example.com","response_length":"648","response_time":"0.253","status":"200"}}"#;
 
+const VALID_DATAGRAM_ERROR: &str = r#"<187>Aug 16 18:37:12 nginx: 2022/08/16 18:37:12 [error] 29#29: *3 open() "/usr/share/nginx/html/missing" failed (2: No such file or directory), client: 172.19.0.1, server: _, request: "GET /missing HTTP/1.1", host: "localhost""#;
+
 #[sqlx::test]
 async fn stores_valid_datagram_for_static_requests(db_pool: PgPool) {
     let server_addr = spawn_test_server(db_pool.clone()).await;
@@ -31,3 +40,978 @@ async fn stores_valid_datagram_with_upstream_data(db_pool: PgPool) {
         .await
         .expect("did not find stored access_log database row");
 }
+
+#[sqlx::test]
+async fn stores_syslog_metadata(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (facility, tag, source_addr): (String, String, String) =
+        sqlx::query_as("SELECT syslog_facility, syslog_tag, host(source_addr) FROM access_log")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored access_log database row");
+    assert_eq!("local7", facility);
+    assert_eq!("nginx", tag);
+    assert_eq!("127.0.0.1", source_addr);
+}
+
+#[sqlx::test]
+async fn stores_valid_error_log_datagram(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    send_datagram(VALID_DATAGRAM_ERROR.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (level, client, tag, source_addr): (String, Option<String>, String, String) =
+        sqlx::query_as("SELECT level, client, syslog_tag, host(source_addr) FROM error_log")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored error_log database row");
+    assert_eq!("error", level);
+    assert_eq!(Some("172.19.0.1".to_owned()), client);
+    assert_eq!("nginx", tag);
+    assert_eq!("127.0.0.1", source_addr);
+}
+
+#[sqlx::test]
+async fn stores_rejected_datagram_for_invalid_json(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    send_datagram(
+        br#"<190>Aug 16 18:35:53 nginx: {"hello": "world"}"#,
+        server_addr,
+    )
+    .await;
+
+    wait_for_insert().await;
+    let (payload_text, error): (String, String) =
+        sqlx::query_as("SELECT payload_text, error FROM rejected_datagram")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored rejected_datagram database row");
+    assert_eq!(
+        r#"<190>Aug 16 18:35:53 nginx: {"hello": "world"}"#,
+        payload_text
+    );
+    assert!(error.contains("missing field"));
+}
+
+#[sqlx::test]
+async fn stores_rejected_datagram_for_invalid_utf8(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    send_datagram(b"<190>Aug 16 18:35:53 nginx: \xff\x00", server_addr).await;
+
+    wait_for_insert().await;
+    let (payload, error): (Vec<u8>, String) =
+        sqlx::query_as("SELECT payload, error FROM rejected_datagram")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored rejected_datagram database row");
+    assert_eq!(b"<190>Aug 16 18:35:53 nginx: \xff\x00".to_vec(), payload);
+    assert!(error.contains("invalid utf-8"));
+}
+
+#[sqlx::test]
+async fn serves_metrics(db_pool: PgPool) {
+    // The exporter installs a global recorder, so this is the only test
+    // that can install it. Other tests running at the same time add to the
+    // counters as well, so only their existence is checked.
+    let metrics_addr = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+    instrumentation::install_exporter(metrics_addr).unwrap();
+
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr).await;
+    wait_for_insert().await;
+
+    let mut stream = tokio::net::TcpStream::connect(metrics_addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+    for metric in [
+        "ngxslpg_datagrams_received_total ",
+        "ngxslpg_bytes_received_total ",
+        r#"ngxslpg_rows_inserted_total{table="access_log"} "#,
+        "ngxslpg_batch_size_bucket",
+        "ngxslpg_insert_duration_seconds_bucket",
+        "ngxslpg_queue_occupancy ",
+    ] {
+        assert!(
+            response.contains(metric),
+            "missing {} in {}",
+            metric,
+            response
+        );
+    }
+}
+
+#[sqlx::test]
+async fn replays_spooled_datagrams_on_startup(db_pool: PgPool) {
+    let spool_dir = std::env::temp_dir().join(format!("ngxslpg-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&spool_dir).unwrap();
+    let spooled = serde_json::json!({
+        "received_at": "2022-08-16T18:35:53Z",
+        "source_addr": "127.0.0.1:1234",
+        "payload": VALID_DATAGRAM_STATIC,
+    });
+    std::fs::write(spool_dir.join("1.jsonl"), format!("{}\n", spooled)).unwrap();
+
+    let mut settings = test_settings();
+    settings.spool_dir = Some(spool_dir.clone());
+    spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    wait_for_insert().await;
+    let _ = sqlx::query("SELECT * FROM access_log")
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find replayed access_log database row");
+    assert!(!spool_dir.join("1.jsonl").exists());
+
+    std::fs::remove_dir_all(spool_dir).unwrap();
+}
+
+#[sqlx::test]
+async fn stores_pending_batch_on_shutdown(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.insert_batch_size = 10;
+    settings.insert_timeout = 60_000;
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = socket.local_addr().unwrap().to_string();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let bridge = tokio::spawn(Bridge::run(
+        db_pool.clone(),
+        settings,
+        vec![socket.into()],
+        async move {
+            let _ = shutdown_rx.await;
+        },
+    ));
+
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr).await;
+    wait_for_insert().await;
+    let rows = sqlx::query("SELECT * FROM access_log")
+        .fetch_all(&db_pool)
+        .await
+        .unwrap();
+    assert!(rows.is_empty(), "batch was stored before shutdown");
+    shutdown_tx.send(()).unwrap();
+
+    bridge
+        .await
+        .unwrap()
+        .expect("bridge did not shut down cleanly");
+    let _ = sqlx::query("SELECT * FROM access_log")
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored access_log database row");
+}
+
+#[tokio::test]
+async fn spools_pending_batch_on_shutdown_if_database_does_not_respond() {
+    // The database accepts connections, but never answers, so acquiring a
+    // connection takes longer than SHUTDOWN_TIMEOUT.
+    let database = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let database_port = database.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut connections = vec![];
+        while let Ok((stream, _)) = database.accept().await {
+            connections.push(stream);
+        }
+    });
+    let db_pool = sqlx::postgres::PgPoolOptions::new()
+        .acquire_timeout(std::time::Duration::from_secs(30))
+        .connect_lazy_with(
+            sqlx::postgres::PgConnectOptions::new()
+                .host("127.0.0.1")
+                .port(database_port),
+        );
+
+    let spool_dir = std::env::temp_dir().join(format!("ngxslpg-test-{}", uuid::Uuid::new_v4()));
+    let mut settings = test_settings();
+    settings.insert_batch_size = 10;
+    settings.insert_timeout = 60_000;
+    settings.spool_dir = Some(spool_dir.clone());
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = socket.local_addr().unwrap().to_string();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let bridge = tokio::spawn(Bridge::run(
+        db_pool,
+        settings,
+        vec![socket.into()],
+        async move {
+            let _ = shutdown_rx.await;
+        },
+    ));
+
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr).await;
+    wait_for_insert().await;
+    shutdown_tx.send(()).unwrap();
+
+    tokio::time::timeout(std::time::Duration::from_secs(2), bridge)
+        .await
+        .expect("bridge did not shut down in time")
+        .unwrap()
+        .expect("bridge did not shut down cleanly");
+    let spooled: String = std::fs::read_dir(&spool_dir)
+        .unwrap()
+        .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+        .collect();
+    assert!(spooled.contains("static_file_example"));
+
+    std::fs::remove_dir_all(spool_dir).unwrap();
+}
+
+#[sqlx::test]
+async fn stores_octet_counted_tcp_message_larger_than_a_datagram(db_pool: PgPool) {
+    let server_addr = spawn_tcp_test_server(db_pool.clone()).await;
+
+    let long_ua = "a".repeat(70_000);
+    let message = VALID_DATAGRAM_STATIC.replace("Mozilla/5.0", &long_ua);
+    let frame = format!("{} {}", message.len(), message);
+    send_stream(frame.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (ua_length,): (i32,) = sqlx::query_as("SELECT length(client_ua) FROM access_log")
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored access_log database row");
+    assert!(ua_length > 70_000);
+}
+
+#[sqlx::test]
+async fn stores_lf_delimited_tcp_messages(db_pool: PgPool) {
+    let server_addr = spawn_tcp_test_server(db_pool.clone()).await;
+
+    let stream = format!("{}\n{}\n", VALID_DATAGRAM_STATIC, VALID_DATAGRAM_ERROR);
+    send_stream(stream.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (access_log_rows, error_log_rows): (i64, i64) = sqlx::query_as(
+        "SELECT (SELECT count(*) FROM access_log), (SELECT count(*) FROM error_log)",
+    )
+    .fetch_one(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(1, access_log_rows);
+    assert_eq!(1, error_log_rows);
+}
+
+#[sqlx::test]
+async fn stores_message_received_over_tls(db_pool: PgPool) {
+    let (files, server_cert) = write_test_certificate();
+    let server_addr = spawn_tls_test_server(db_pool.clone(), files.clone()).await;
+
+    let frame = format!("{} {}", VALID_DATAGRAM_STATIC.len(), VALID_DATAGRAM_STATIC);
+    send_tls_stream(frame.as_bytes(), server_addr, server_cert)
+        .await
+        .unwrap();
+
+    wait_for_insert().await;
+    let _ = sqlx::query("SELECT * FROM access_log")
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored access_log database row");
+
+    std::fs::remove_dir_all(files.cert.parent().unwrap()).unwrap();
+}
+
+#[sqlx::test]
+async fn rejects_tls_clients_without_certificate_if_client_ca_is_set(db_pool: PgPool) {
+    let (mut files, server_cert) = write_test_certificate();
+    files.client_ca = Some(files.cert.clone());
+    let server_addr = spawn_tls_test_server(db_pool.clone(), files.clone()).await;
+
+    // Depending on the TLS version, the client might only notice after it
+    // already sent something, so this can't check for an error here.
+    let _ = send_tls_stream(VALID_DATAGRAM_STATIC.as_bytes(), server_addr, server_cert).await;
+
+    wait_for_insert().await;
+    let rows = sqlx::query("SELECT * FROM access_log")
+        .fetch_all(&db_pool)
+        .await
+        .unwrap();
+    assert!(rows.is_empty());
+
+    std::fs::remove_dir_all(files.cert.parent().unwrap()).unwrap();
+}
+
+#[sqlx::test]
+async fn stores_datagrams_from_all_sockets(db_pool: PgPool) {
+    let udp_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let udp_addr = udp_socket.local_addr().unwrap().to_string();
+    let unix_path =
+        std::env::temp_dir().join(format!("ngxslpg-test-{}.sock", uuid::Uuid::new_v4()));
+    let unix_socket = tokio::net::UnixDatagram::bind(&unix_path).unwrap();
+
+    tokio::spawn(Bridge::run(
+        db_pool.clone(),
+        test_settings(),
+        vec![udp_socket.into(), unix_socket.into()],
+        std::future::pending(),
+    ));
+
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), udp_addr).await;
+    let client = tokio::net::UnixDatagram::unbound().unwrap();
+    client
+        .send_to(VALID_DATAGRAM_UPSTREAM.as_bytes(), &unix_path)
+        .await
+        .unwrap();
+
+    wait_for_insert().await;
+    let (rows,): (i64,) = sqlx::query_as("SELECT count(*) FROM access_log")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(2, rows);
+
+    std::fs::remove_file(unix_path).unwrap();
+}
+
+#[sqlx::test]
+async fn drops_datagrams_from_sources_not_allowed(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.allowed_sources = vec!["10.0.0.0/8".parse().unwrap()];
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let rows = sqlx::query("SELECT * FROM access_log")
+        .fetch_all(&db_pool)
+        .await
+        .unwrap();
+    assert!(rows.is_empty());
+}
+
+#[sqlx::test]
+async fn drops_datagrams_above_rate_limit(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.rate_limit = Some(1);
+    settings.rate_limit_burst = Some(2);
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    for _ in 0..5 {
+        send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr.clone()).await;
+    }
+
+    wait_for_insert().await;
+    let (rows,): (i64,) = sqlx::query_as("SELECT count(*) FROM access_log")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(2, rows);
+}
+
+#[sqlx::test]
+async fn rejects_datagrams_without_valid_auth_token(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.auth_keys = vec!["old-secret".to_owned(), "new-secret".to_owned()];
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    let with_token = |token: &str| {
+        VALID_DATAGRAM_STATIC.replace(
+            r#"{"hostname""#,
+            &format!(r#"{{"auth":"{}","hostname""#, token),
+        )
+    };
+    send_datagram(with_token("new-secret").as_bytes(), server_addr.clone()).await;
+    send_datagram(with_token("wrong-secret").as_bytes(), server_addr.clone()).await;
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (access_log_rows,): (i64,) = sqlx::query_as("SELECT count(*) FROM access_log")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, access_log_rows);
+
+    let rejected: Vec<(String, String, Vec<u8>)> =
+        sqlx::query_as("SELECT error, payload_text, payload FROM rejected_datagram")
+            .fetch_all(&db_pool)
+            .await
+            .unwrap();
+    assert_eq!(2, rejected.len());
+    for (error, payload_text, payload) in rejected {
+        assert!(error.starts_with("authentication failed"));
+        assert!(!payload_text.contains("wrong-secret"));
+        assert!(String::from_utf8(payload).is_ok_and(|payload| !payload.contains("wrong-secret")));
+    }
+    let (redacted,): (i64,) = sqlx::query_as(
+        r#"SELECT count(*) FROM rejected_datagram WHERE payload_text LIKE '%"auth":"[REDACTED]"%'"#,
+    )
+    .fetch_one(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(1, redacted);
+}
+
+#[sqlx::test]
+async fn stores_mapped_fields_in_their_own_columns(db_pool: PgPool) {
+    let mapping_file =
+        std::env::temp_dir().join(format!("ngxslpg-test-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &mapping_file,
+        r#"
+        [[field]]
+        path = "req.id"
+        column = "req_id"
+        type = "text"
+
+        [[field]]
+        path = "ssl.reused"
+        column = "ssl_session_reused"
+        type = "boolean"
+        "#,
+    )
+    .unwrap();
+
+    let mut settings = test_settings();
+    settings.field_mapping_file = Some(mapping_file.clone());
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    let datagram =
+        VALID_DATAGRAM_STATIC.replace(r#""method":"GET","#, r#""method":"GET","id":"4f6a3c2e","#);
+    let datagram = datagram.replace(r#"{"hostname""#, r#"{"ssl":{"reused":"1"},"hostname""#);
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (req_id, ssl_session_reused, extra_is_null): (String, bool, bool) =
+        sqlx::query_as("SELECT req_id, ssl_session_reused, extra IS NULL FROM access_log")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored access_log database row");
+    assert_eq!("4f6a3c2e", req_id);
+    assert!(ssl_session_reused);
+    assert!(extra_is_null, "mapped fields should not end up in extra");
+
+    std::fs::remove_file(mapping_file).unwrap();
+}
+
+#[sqlx::test]
+async fn stores_mapped_fields_in_columns_named_like_reserved_words(db_pool: PgPool) {
+    let mapping_file =
+        std::env::temp_dir().join(format!("ngxslpg-test-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &mapping_file,
+        r#"
+        [[field]]
+        path = "user"
+        column = "user"
+        type = "text"
+
+        [[field]]
+        path = "order"
+        column = "order"
+        type = "integer"
+        "#,
+    )
+    .unwrap();
+
+    let mut settings = test_settings();
+    settings.field_mapping_file = Some(mapping_file.clone());
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    let datagram =
+        VALID_DATAGRAM_STATIC.replace(r#"{"hostname""#, r#"{"user":"meow","order":"3","hostname""#);
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (user, order): (String, i32) = sqlx::query_as(r#"SELECT "user", "order" FROM access_log"#)
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored access_log database row");
+    assert_eq!("meow", user);
+    assert_eq!(3, order);
+
+    std::fs::remove_file(mapping_file).unwrap();
+}
+
+#[sqlx::test]
+async fn stores_unknown_fields_in_extra(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    let datagram = VALID_DATAGRAM_STATIC
+        .replace(r#""method":"GET","#, r#""method":"GET","id":"4f6a3c2e","#)
+        .replace(r#"{"hostname""#, r#"{"tenant":"meow","hostname""#);
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (tenant, req_id): (String, String) =
+        sqlx::query_as("SELECT extra->>'tenant', extra->'req'->>'id' FROM access_log")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored access_log database row");
+    assert_eq!("meow", tenant);
+    assert_eq!("4f6a3c2e", req_id);
+}
+
+#[sqlx::test]
+async fn stores_entries_with_missing_fields_in_lenient_mode(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.parsing_mode = ParsingMode::Lenient;
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    send_datagram(
+        br#"<190>Aug 16 18:35:53 nginx: {"hostname":"a970744801bb","req":{"host":"localhost","method":"GET","uri":"/"},"res":{"status":"200"}}"#,
+        server_addr,
+    )
+    .await;
+
+    wait_for_insert().await;
+    let (req_uri, res_status, upstream_status, event_ts_is_recent): (String, i32, Option<i32>, bool) =
+        sqlx::query_as(
+            "SELECT req_uri, res_status, upstream_status, event_ts > now() - interval '1 minute' FROM access_log",
+        )
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored access_log database row");
+    assert_eq!("/", req_uri);
+    assert_eq!(200, res_status);
+    assert!(upstream_status.is_none());
+    assert!(
+        event_ts_is_recent,
+        "missing ts should fall back to the receive time"
+    );
+}
+
+#[sqlx::test]
+async fn rejects_junk_in_lenient_mode(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.parsing_mode = ParsingMode::Lenient;
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    send_datagram(
+        br#"<190>Aug 16 18:35:53 nginx: {"hello":"world"}"#,
+        server_addr.clone(),
+    )
+    .await;
+    send_datagram(
+        br#"<190>Aug 16 18:35:53 nginx: {"req":{"uri":"/"},"res":{"status":"200"}}"#,
+        server_addr,
+    )
+    .await;
+
+    wait_for_insert().await;
+    let hostnames: Vec<(Option<String>,)> = sqlx::query_as("SELECT hostname FROM access_log")
+        .fetch_all(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(vec![(None,)], hostnames);
+
+    let (error,): (String,) = sqlx::query_as("SELECT error FROM rejected_datagram")
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored rejected_datagram database row");
+    assert_eq!(
+        "missing fields `ts`, `req`, `res`, not an access_log entry",
+        error
+    );
+}
+
+#[sqlx::test]
+async fn rejects_entries_with_missing_fields_in_strict_mode(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    let (without_upstream, _) = VALID_DATAGRAM_STATIC.split_once(r#","upstream""#).unwrap();
+    send_datagram(format!("{}}}", without_upstream).as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (error,): (String,) = sqlx::query_as("SELECT error FROM rejected_datagram")
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored rejected_datagram database row");
+    assert_eq!("missing field `upstream`", error);
+}
+
+#[sqlx::test]
+async fn stores_combined_log_format_entries(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    send_datagram(
+        br#"<190>Aug 16 18:35:53 nginx: 172.19.0.1 - - [16/Aug/2022:18:35:53 +0000] "GET /static_file_example HTTP/1.1" 304 0 "-" "curl/8.0""#,
+        server_addr.clone(),
+    )
+    .await;
+    send_datagram(VALID_DATAGRAM_ERROR.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (req_uri, res_status, client_ua): (String, i32, String) =
+        sqlx::query_as("SELECT req_uri, res_status, client_ua FROM access_log")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored access_log database row");
+    assert_eq!("/static_file_example", req_uri);
+    assert_eq!(304, res_status);
+    assert_eq!("curl/8.0", client_ua);
+
+    let (error_log_rows,): (i64,) = sqlx::query_as("SELECT count(*) FROM error_log")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, error_log_rows);
+}
+
+#[sqlx::test]
+async fn rejects_other_formats_for_combined_log_tags(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.combined_log_tags = vec!["nginx".to_owned()];
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    send_datagram(VALID_DATAGRAM_ERROR.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (error_log_rows, rejected_rows): (i64, i64) = sqlx::query_as(
+        "SELECT (SELECT count(*) FROM error_log), (SELECT count(*) FROM rejected_datagram)",
+    )
+    .fetch_one(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(0, error_log_rows);
+    assert_eq!(1, rejected_rows);
+}
+
+#[sqlx::test]
+async fn stores_all_upstream_attempts(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    let datagram = VALID_DATAGRAM_UPSTREAM
+        .replace(
+            r#""addr":"93.184.216.34:80""#,
+            r#""addr":"10.0.0.1:80, 93.184.216.34:80""#,
+        )
+        .replace(
+            r#""response_time":"0.253""#,
+            r#""response_time":"-, 0.253""#,
+        )
+        .replace(r#""status":"200"}}"#, r#""status":"502, 200"}}"#);
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (status, status_attempts, addr, addr_attempts): (i32, Vec<i32>, String, Vec<String>) =
+        sqlx::query_as(
+            "SELECT upstream_status, upstream_status_attempts, host(upstream_addr), upstream_addr_attempts FROM access_log",
+        )
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored access_log database row");
+    assert_eq!(200, status);
+    assert_eq!(vec![502, 200], status_attempts);
+    assert_eq!("93.184.216.34", addr);
+    assert_eq!(vec!["10.0.0.1:80", "93.184.216.34:80"], addr_attempts);
+
+    let (response_time_attempts,): (Vec<Option<f64>>,) =
+        sqlx::query_as("SELECT upstream_response_time_attempts FROM access_log")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+    assert_eq!(vec![None, Some(0.253)], response_time_attempts);
+}
+
+#[sqlx::test]
+async fn stores_addresses_as_inet(db_pool: PgPool) {
+    let server_addr = spawn_test_server(db_pool.clone()).await;
+
+    let datagram = VALID_DATAGRAM_UPSTREAM
+        .replace(
+            r#""addr":"93.184.216.34:80""#,
+            r#""addr":"[2001:db8::1]:8080""#,
+        )
+        .replace(r#""addr":"172.19.0.1""#, r#""addr":"::ffff:172.19.0.1""#);
+    send_datagram(datagram.as_bytes(), server_addr.clone()).await;
+    send_datagram(
+        VALID_DATAGRAM_UPSTREAM
+            .replace(
+                r#""addr":"93.184.216.34:80""#,
+                r#""addr":"unix:/run/app.sock""#,
+            )
+            .as_bytes(),
+        server_addr,
+    )
+    .await;
+
+    wait_for_insert().await;
+    let rows: Vec<(Option<IpAddr>, Option<i32>, bool)> = sqlx::query_as(
+        "SELECT upstream_addr, upstream_port, upstream_is_unix FROM access_log
+         WHERE client_addr << '172.16.0.0/12'::inet ORDER BY upstream_is_unix",
+    )
+    .fetch_all(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        vec![
+            (Some("2001:db8::1".parse().unwrap()), Some(8080), false),
+            (None, None, true),
+        ],
+        rows
+    );
+}
+
+#[sqlx::test]
+async fn derives_real_client_addr(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.trusted_proxies = vec![
+        "172.16.0.0/12".parse().unwrap(),
+        "10.0.0.0/8".parse().unwrap(),
+    ];
+    settings.real_ip_recursive = true;
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    let datagram = VALID_DATAGRAM_STATIC.replace(
+        r#""forwarded_for":"""#,
+        r#""forwarded_for":"203.0.113.7, unknown, 198.51.100.1, 10.0.0.2""#,
+    );
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (forwarded_for_addrs, real_addr): (Vec<Option<IpAddr>>, IpAddr) =
+        sqlx::query_as("SELECT client_forwarded_for_addrs, client_real_addr FROM access_log")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored access_log database row");
+    assert_eq!(
+        vec![
+            Some("203.0.113.7".parse().unwrap()),
+            None,
+            Some("198.51.100.1".parse().unwrap()),
+            Some("10.0.0.2".parse().unwrap()),
+        ],
+        forwarded_for_addrs
+    );
+    assert_eq!("198.51.100.1".parse::<IpAddr>().unwrap(), real_addr);
+}
+
+#[sqlx::test]
+async fn splits_and_redacts_req_uri(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.redact_query_params = vec!["token".to_owned()];
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    let datagram = VALID_DATAGRAM_STATIC
+        .replace(
+            r#""uri":"/static_file_example""#,
+            r#""uri":"/static_file_example?page=2&token=s3cr3t""#,
+        )
+        .replace(
+            r#""referer":"""#,
+            r#""referer":"https://localhost/reset?token=abc#top""#,
+        );
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (uri, path, query, referer, referer_path): (String, String, String, String, String) =
+        sqlx::query_as(
+            "SELECT req_uri, req_path, req_query, client_referer, referer_path FROM access_log",
+        )
+        .fetch_one(&db_pool)
+        .await
+        .expect("did not find stored access_log database row");
+    assert_eq!("/static_file_example?page=2&token=[REDACTED]", uri);
+    assert_eq!("/static_file_example", path);
+    assert_eq!("page=2&token=[REDACTED]", query);
+    assert_eq!("https://localhost/reset?token=[REDACTED]#top", referer);
+    assert_eq!("/reset", referer_path);
+}
+
+#[sqlx::test]
+async fn redacts_query_params_in_error_log(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.redact_query_params = vec!["token".to_owned()];
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    let datagram = VALID_DATAGRAM_ERROR
+        .replace(
+            "GET /missing HTTP/1.1",
+            "GET /missing?page=2&token=s3cr3t HTTP/1.1",
+        )
+        .replace(
+            r#"host: "localhost""#,
+            r#"subrequest: "/auth?token=s3cr3t", host: "localhost", referrer: "https://example.com/?token=s3cr3t""#,
+        );
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (request, subrequest, referrer): (String, String, String) =
+        sqlx::query_as("SELECT request, subrequest, referrer FROM error_log")
+            .fetch_one(&db_pool)
+            .await
+            .expect("did not find stored error_log database row");
+    assert_eq!("GET /missing?page=2&token=[REDACTED] HTTP/1.1", request);
+    assert_eq!("/auth?token=[REDACTED]", subrequest);
+    assert_eq!("https://example.com/?token=[REDACTED]", referrer);
+}
+
+#[sqlx::test]
+async fn stores_parsed_user_agents(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.parse_user_agents = true;
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr.clone()).await;
+    let datagram = VALID_DATAGRAM_STATIC.replace(
+        "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:105.0) Gecko/20100101 Firefox/105.0",
+        "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+    );
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let rows: Vec<(String, String, Option<String>, bool)> = sqlx::query_as(
+        "SELECT ua_browser, ua_browser_version, ua_os, ua_is_bot FROM access_log ORDER BY ua_is_bot",
+    )
+    .fetch_all(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        vec![
+            (
+                "Firefox".to_owned(),
+                "105.0".to_owned(),
+                Some("Mac OS X".to_owned()),
+                false
+            ),
+            ("bingbot".to_owned(), "2.0".to_owned(), None, true),
+        ],
+        rows
+    );
+}
+
+#[sqlx::test]
+async fn anonymizes_client_addresses(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.trusted_proxies = vec!["172.16.0.0/12".parse().unwrap()];
+    settings.ip_anonymization = IpAnonymization::Truncate;
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    let datagram = VALID_DATAGRAM_STATIC.replace(
+        r#""forwarded_for":"""#,
+        r#""forwarded_for":"2001:db8:1:2::1, 198.51.100.123""#,
+    );
+    send_datagram(datagram.as_bytes(), server_addr).await;
+
+    wait_for_insert().await;
+    let (addr, forwarded_for, forwarded_for_addrs, real_addr): (
+        IpAddr,
+        String,
+        Vec<IpAddr>,
+        IpAddr,
+    ) = sqlx::query_as(
+        "SELECT client_addr, client_forwarded_for, client_forwarded_for_addrs, client_real_addr FROM access_log",
+    )
+    .fetch_one(&db_pool)
+    .await
+    .expect("did not find stored access_log database row");
+    assert_eq!("172.19.0.0".parse::<IpAddr>().unwrap(), addr);
+    assert_eq!("2001:db8:1::, 198.51.100.0", forwarded_for);
+    assert_eq!(
+        vec![
+            "2001:db8:1::".parse::<IpAddr>().unwrap(),
+            "198.51.100.0".parse().unwrap()
+        ],
+        forwarded_for_addrs
+    );
+    assert_eq!("198.51.100.0".parse::<IpAddr>().unwrap(), real_addr);
+}
+
+#[sqlx::test]
+async fn stores_referer_parts(db_pool: PgPool) {
+    let mut settings = test_settings();
+    settings.own_domains = vec!["example.com".to_owned()];
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    for referer in [
+        "https://www.example.com/blog/?utm_source=meow",
+        "https://search.example.org/results?q=nginx",
+    ] {
+        let datagram = VALID_DATAGRAM_STATIC
+            .replace(r#""referer":"""#, &format!(r#""referer":"{}""#, referer));
+        send_datagram(datagram.as_bytes(), server_addr.clone()).await;
+    }
+
+    wait_for_insert().await;
+    let rows: Vec<(String, String, String, bool)> = sqlx::query_as(
+        "SELECT referer_scheme, referer_host, referer_path, referer_is_internal FROM access_log ORDER BY referer_host",
+    )
+    .fetch_all(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        vec![
+            (
+                "https".to_owned(),
+                "search.example.org".to_owned(),
+                "/results".to_owned(),
+                false
+            ),
+            (
+                "https".to_owned(),
+                "www.example.com".to_owned(),
+                "/blog/".to_owned(),
+                true
+            ),
+        ],
+        rows
+    );
+}
+
+#[sqlx::test]
+async fn applies_sampling_rules(db_pool: PgPool) {
+    let rules_file =
+        std::env::temp_dir().join(format!("ngxslpg-test-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &rules_file,
+        r#"
+        [[rule]]
+        path_prefix = "/static"
+        action = "drop"
+
+        [[rule]]
+        status = "200-299"
+        sample_rate = 0.5
+        "#,
+    )
+    .unwrap();
+
+    let mut settings = test_settings();
+    settings.sampling_rules_file = Some(rules_file.clone());
+    let server_addr = spawn_test_server_with_settings(db_pool.clone(), settings).await;
+
+    send_datagram(VALID_DATAGRAM_STATIC.as_bytes(), server_addr.clone()).await;
+    for i in 0..20 {
+        let datagram = VALID_DATAGRAM_UPSTREAM.replace(
+            "/upstream_proxy_example",
+            &format!("/upstream_proxy_example?page={}", i),
+        );
+        send_datagram(datagram.as_bytes(), server_addr.clone()).await;
+    }
+
+    // Sampling is based on the hash of the log line, so which lines end up
+    // in the kept half is always the same.
+    wait_for_insert().await;
+    let rows: Vec<(String, f64)> = sqlx::query_as("SELECT req_path, sample_rate FROM access_log")
+        .fetch_all(&db_pool)
+        .await
+        .unwrap();
+    assert!(
+        (1..20).contains(&rows.len()),
+        "kept {} of 20 entries",
+        rows.len()
+    );
+    assert!(
+        rows.iter()
+            .all(|row| *row == ("/upstream_proxy_example".to_owned(), 0.5))
+    );
+
+    std::fs::remove_file(rules_file).unwrap();
+}